@@ -1,4 +1,7 @@
-use kafka_buffer::encode_request;
+use kafka_buffer::compression::{BodyCompression, Codec};
+use kafka_buffer::{decode_request, encode_compressed_request, encode_request};
+use kafka_buffer::encoding::Encoding;
+use kafka_buffer::headers::HeaderCapture;
 
 use criterion::{criterion_group, criterion_main, Criterion};
 use std::hint::black_box;
@@ -156,5 +159,79 @@ fn criterion_benchmark(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, criterion_benchmark);
+/// each wire encoding, both ways
+fn encodings_benchmark(c: &mut Criterion) {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    headers.insert(USER_AGENT, HeaderValue::from_static("curl/8.7.1"));
+    let want = HeaderCapture::names(vec![CONTENT_TYPE, USER_AGENT]);
+    let payloads = [
+        ("100", PAYLOAD_100.repeat(1)),
+        ("1k", PAYLOAD_100.repeat(10)),
+        ("10k", PAYLOAD_100.repeat(100)),
+    ];
+
+    let mut group = c.benchmark_group("encodings");
+    for encoding in [Encoding::Capnp, Encoding::CapnpPacked, Encoding::Json, Encoding::Protobuf] {
+        for (size, payload) in &payloads {
+            group.bench_function(format!("{}/encode/{}", encoding.name(), size), |b| {
+                b.iter(|| encoding.encode_request(black_box(payload.as_bytes()), black_box(&headers), black_box(&want), None))
+            });
+            let encoded = encoding.encode_request(payload.as_bytes(), &headers, &want, None);
+            group.bench_function(format!("{}/decode/{}", encoding.name(), size), |b| {
+                b.iter(|| encoding.decode(black_box(&encoded)))
+            });
+        }
+    }
+    group.finish();
+}
+
+/// body compression, with the encoded size of each, for the size/CPU trade-off
+fn compression_benchmark(c: &mut Criterion) {
+    let payloads = [
+        ("100", PAYLOAD_100.repeat(1)),
+        ("1k", PAYLOAD_100.repeat(10)),
+        ("10k", PAYLOAD_100.repeat(100)),
+    ];
+    let codecs = [
+        ("none", None),
+        ("zstd", Some(BodyCompression { codec: Codec::Zstd, above: 0 })),
+        ("gzip", Some(BodyCompression { codec: Codec::Gzip, above: 0 })),
+    ];
+
+    // once, before the timed groups
+    println!("encoded bytes, by payload size:");
+    for (name, compression) in &codecs {
+        let sizes: Vec<String> = payloads
+            .iter()
+            .map(|(size, payload)| {
+                let encoded = encode_compressed_request(payload.as_bytes(), &[], compression.as_ref());
+                format!("{}: {}", size, encoded.len())
+            })
+            .collect();
+        println!("  {:<4} {}", name, sizes.join(", "));
+    }
+
+    let mut group = c.benchmark_group("compression");
+    for (name, compression) in &codecs {
+        for (size, payload) in &payloads {
+            let encoded = encode_compressed_request(payload.as_bytes(), &[], compression.as_ref());
+            group.bench_function(format!("{}/encode/{}", name, size), |b| {
+                b.iter(|| {
+                    encode_compressed_request(
+                        black_box(payload.as_bytes()),
+                        black_box(&[]),
+                        black_box(compression.as_ref()),
+                    )
+                })
+            });
+            group.bench_function(format!("{}/decode/{}", name, size), |b| {
+                b.iter(|| decode_request(black_box(Some(&encoded))))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark, encodings_benchmark, compression_benchmark);
 criterion_main!(benches);
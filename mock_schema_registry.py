@@ -0,0 +1,81 @@
+#!/usr/bin/env python3
+"""An in-memory stand-in for a Confluent schema registry, with the endpoints
+kafka-buffer uses, for trying the avro encoding locally.  Compatibility is
+BACKWARD, the registry's default, for record schemas: each field of the new
+schema must be in the latest version with the same type, or have a
+default."""
+
+import json
+import re
+import sys
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+schemas = []  # id - 1 => schema text
+subjects = {}  # subject => [id]
+
+# the promotions Avro allows, writer type => reader types
+PROMOTIONS = {"int": {"long", "float", "double"}, "long": {"float", "double"},
+              "float": {"double"}, "string": {"bytes"}, "bytes": {"string"}}
+
+
+def promotable(writer_type, reader_type):
+    return isinstance(writer_type, str) and isinstance(reader_type, str) and reader_type in PROMOTIONS.get(writer_type, ())
+
+
+def incompatibilities(writer_text, reader_text):
+    """why the reader can't read what the writer wrote, empty if it can"""
+    writer, reader = json.loads(writer_text), json.loads(reader_text)
+    if writer.get("type") != "record" or reader.get("type") != "record":
+        return [] if writer == reader else ["schemas differ"]
+    written = {f["name"]: f["type"] for f in writer["fields"]}
+    messages = []
+    for field in reader["fields"]:
+        name, type = field["name"], field["type"]
+        if name not in written:
+            if "default" not in field:
+                messages.append(f"field {name} is new, and has no default")
+        elif written[name] != type and not promotable(written[name], type):
+            messages.append(f"field {name} was {json.dumps(written[name])}, now {json.dumps(type)}")
+    return messages
+
+
+class Registry(BaseHTTPRequestHandler):
+    def reply(self, status, body):
+        data = json.dumps(body).encode()
+        self.send_response(status)
+        self.send_header("Content-Type", "application/vnd.schemaregistry.v1+json")
+        self.send_header("Content-Length", str(len(data)))
+        self.end_headers()
+        self.wfile.write(data)
+
+    def not_found(self):
+        self.reply(404, {"error_code": 40401, "message": "Subject not found."})
+
+    def do_GET(self):
+        m = re.fullmatch(r"/schemas/ids/(\d+)", self.path)
+        if m and 0 < int(m[1]) <= len(schemas):
+            return self.reply(200, {"schema": schemas[int(m[1]) - 1]})
+        self.not_found()
+
+    def do_POST(self):
+        body = json.loads(self.rfile.read(int(self.headers["Content-Length"])))
+        m = re.fullmatch(r"/compatibility/subjects/([^/]+)/versions/latest", self.path)
+        if m:
+            if m[1] not in subjects:
+                return self.not_found()
+            messages = incompatibilities(schemas[subjects[m[1]][-1] - 1], body["schema"])
+            return self.reply(200, {"is_compatible": not messages, "messages": messages})
+        m = re.fullmatch(r"/subjects/([^/]+)/versions", self.path)
+        if m:
+            if m[1] in subjects and incompatibilities(schemas[subjects[m[1]][-1] - 1], body["schema"]):
+                return self.reply(409, {"error_code": 409, "message": "Schema being registered is incompatible with an earlier schema"})
+            if body["schema"] not in schemas:
+                schemas.append(body["schema"])
+            id = schemas.index(body["schema"]) + 1
+            if id not in subjects.setdefault(m[1], []):
+                subjects[m[1]].append(id)
+            return self.reply(200, {"id": id})
+        self.not_found()
+
+
+HTTPServer(("127.0.0.1", int(sys.argv[1]) if len(sys.argv) > 1 else 8081), Registry).serve_forever()
@@ -0,0 +1,437 @@
+//! A durable record of every request, in rotating files on local disk,
+//! which `replay` can write back to Kafka.
+//!
+//! Each topic has a directory of segments named by the time they were
+//! opened, like `archive/foo_topic/20240901T120000.000Z.jsonl.zst`.  Next to
+//! each segment, `<segment>.index.json` has the first and last offset from
+//! each partition, and is rewritten after every write.  Segments keep each
+//! buffer message's headers too, so `replay` writes it back as it was.
+
+use crate::config::Route;
+use crate::observability::hist_time_since;
+use crate::sink::{JobSink, Outcome, Record};
+use crate::DecodedRequest;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use prometheus::{register_histogram, register_int_counter, Histogram, IntCounter};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::*;
+
+lazy_static! {
+    static ref ARCHIVE_DURATION_S: Histogram =
+        register_histogram!("archive_duration_s", "duration of writes to archive segments, including fsync").unwrap();
+    static ref ARCHIVE_ROTATIONS: IntCounter =
+        register_int_counter!("archive_rotations", "number of archive segments closed").unwrap();
+}
+
+/// how each request is written in a segment
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArchiveFormat {
+    /// one JSON object per line, with topic, partition, offset, headers,
+    /// buffer_headers as a list of [name, value] pairs, and body, or
+    /// body_base64 if the body isn't UTF-8
+    Jsonl,
+    /// the BufferedRequest's length as a big-endian u32, then the length of
+    /// the buffer headers as a big-endian u16, the buffer headers as a JSON
+    /// list of [name, value] pairs, and the BufferedRequest
+    Capnp,
+}
+
+impl ArchiveFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Jsonl => "jsonl",
+            ArchiveFormat::Capnp => "capnp",
+        }
+    }
+}
+
+enum Writer {
+    Plain(BufWriter<File>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl Writer {
+    fn get_mut(&mut self) -> &mut dyn Write {
+        match self {
+            Writer::Plain(w) => w,
+            Writer::Zstd(w) => w,
+        }
+    }
+
+    /// flush through to the disk, so the segment can be read to here
+    fn sync(&mut self) -> io::Result<()> {
+        self.get_mut().flush()?;
+        match self {
+            Writer::Plain(w) => w.get_ref().sync_data(),
+            Writer::Zstd(w) => w.get_ref().get_ref().sync_data(),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        let file = match self {
+            Writer::Plain(w) => w.into_inner().map_err(|err| err.into_error())?,
+            Writer::Zstd(w) => w.finish()?.into_inner().map_err(|err| err.into_error())?,
+        };
+        file.sync_all()
+    }
+}
+
+/// the segment a topic is writing to now
+struct Segment {
+    path: PathBuf,
+    writer: Writer,
+    opened: Instant,
+    /// before compression
+    bytes: u64,
+    records: u64,
+    /// partition => (first, last) offset
+    offsets: BTreeMap<i32, (i64, i64)>,
+}
+
+pub struct Archive {
+    dir: PathBuf,
+    format: ArchiveFormat,
+    /// rotate after this many bytes, before compression
+    max_bytes: u64,
+    max_age: Duration,
+    /// zstd level, if segments are compressed
+    zstd_level: Option<i32>,
+    /// topic => open segment
+    segments: Mutex<HashMap<String, Segment>>,
+}
+
+impl Archive {
+    /// Writes block on disk in block_in_place, so this needs a multi-thread
+    /// tokio runtime.
+    pub fn new(dir: &Path, format: ArchiveFormat, max_bytes: u64, max_age: Duration, zstd_level: Option<i32>) -> Archive {
+        crate::assert_multi_thread("Archive");
+        Archive {
+            dir: dir.to_owned(),
+            format,
+            max_bytes,
+            max_age,
+            zstd_level,
+            segments: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn open(&self, topic: &str) -> anyhow::Result<Segment> {
+        let dir = self.dir.join(topic);
+        fs::create_dir_all(&dir)?;
+        let mut name = format!("{}.{}", Utc::now().format("%Y%m%dT%H%M%S%.3fZ"), self.format.extension());
+        if self.zstd_level.is_some() {
+            name.push_str(".zst");
+        }
+        let path = dir.join(name);
+        let file = BufWriter::new(File::options().create_new(true).write(true).open(&path)?);
+        let writer = match self.zstd_level {
+            None => Writer::Plain(file),
+            Some(level) => Writer::Zstd(zstd::Encoder::new(file, level)?),
+        };
+        info!("opened archive segment {}", path.display());
+        Ok(Segment {
+            path,
+            writer,
+            opened: Instant::now(),
+            bytes: 0,
+            records: 0,
+            offsets: BTreeMap::new(),
+        })
+    }
+
+    /// Append records to the topic's segment, then sync, rotating first if
+    /// the segment is full or old.  Blocks on disk writes.
+    fn write(&self, segments: &mut HashMap<String, Segment>, topic: &str, records: &[Record]) -> anyhow::Result<()> {
+        let full = segments
+            .get(topic)
+            .is_some_and(|s| s.bytes >= self.max_bytes || s.opened.elapsed() >= self.max_age);
+        if full {
+            if let Some(segment) = segments.remove(topic) {
+                close(segment)?;
+            }
+        }
+        if !segments.contains_key(topic) {
+            segments.insert(topic.to_owned(), self.open(topic)?);
+        }
+        let segment = segments.get_mut(topic).expect("opened above");
+        for record in records {
+            let entry = match self.format {
+                ArchiveFormat::Jsonl => {
+                    let mut line = jsonl_entry(record).to_string().into_bytes();
+                    line.push(b'\n');
+                    line
+                }
+                ArchiveFormat::Capnp => {
+                    let message = record.request.encode();
+                    let headers = serde_json::to_vec(&record.headers)?;
+                    let headers_len: u16 = headers
+                        .len()
+                        .try_into()
+                        .map_err(|_| anyhow!("{} bytes of buffer headers, more than fit", headers.len()))?;
+                    let mut entry = (message.len() as u32).to_be_bytes().to_vec();
+                    entry.extend(headers_len.to_be_bytes());
+                    entry.extend(headers);
+                    entry.extend(message);
+                    entry
+                }
+            };
+            segment.writer.get_mut().write_all(&entry)?;
+            segment.bytes += entry.len() as u64;
+            segment.records += 1;
+            segment
+                .offsets
+                .entry(record.partition)
+                .and_modify(|(_, last)| *last = record.offset)
+                .or_insert((record.offset, record.offset));
+        }
+        segment.writer.sync()?;
+        write_index(segment, false)
+    }
+}
+
+fn jsonl_entry(record: &Record) -> Value {
+    let mut entry = json!({
+        "topic": record.topic,
+        "partition": record.partition,
+        "offset": record.offset,
+        "headers": record.request.headers,
+        "buffer_headers": record.headers,
+    });
+    match std::str::from_utf8(&record.request.body) {
+        Ok(body) => entry["body"] = json!(body),
+        Err(_) => entry["body_base64"] = json!(STANDARD.encode(&record.request.body)),
+    }
+    if let Some(reason) = &record.error {
+        entry["error"] = json!(reason);
+    }
+    entry
+}
+
+fn index_path(segment: &Path) -> PathBuf {
+    let mut name = segment.as_os_str().to_owned();
+    name.push(".index.json");
+    PathBuf::from(name)
+}
+
+/// replace the index, so a reader never sees half of one
+fn write_index(segment: &Segment, closed: bool) -> anyhow::Result<()> {
+    let partitions: Map<String, Value> = segment
+        .offsets
+        .iter()
+        .map(|(p, (first, last))| (p.to_string(), json!({"first": first, "last": last})))
+        .collect();
+    let index = json!({
+        "segment": segment.path.file_name().map(|n| n.to_string_lossy()),
+        "records": segment.records,
+        "partitions": partitions,
+        "closed": closed,
+    });
+    let path = index_path(&segment.path);
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, index.to_string())?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+fn close(segment: Segment) -> anyhow::Result<()> {
+    write_index(&segment, true)?;
+    info!("closed archive segment {} with {} records", segment.path.display(), segment.records);
+    segment.writer.finish()?;
+    ARCHIVE_ROTATIONS.inc();
+    Ok(())
+}
+
+#[async_trait]
+impl JobSink for Archive {
+    async fn push(&self, route: &Route, record: &Record) -> anyhow::Result<Outcome> {
+        self.push_batch(route, std::slice::from_ref(record)).await.remove(0)
+    }
+
+    /// One sync for the whole batch.  Records are written in order, so the
+    /// offset committed after them is on disk.
+    async fn push_batch(&self, route: &Route, records: &[Record]) -> Vec<anyhow::Result<Outcome>> {
+        let mut segments = self.segments.lock().await;
+        let start = Instant::now();
+        let r_write = tokio::task::block_in_place(|| self.write(&mut segments, &route.topic, records));
+        hist_time_since(&ARCHIVE_DURATION_S, start);
+        match r_write {
+            Ok(()) => records
+                .iter()
+                .map(|record| match record.error {
+                    None => Ok(Outcome::Enqueued),
+                    Some(_) => Ok(Outcome::DeadLettered),
+                })
+                .collect(),
+            Err(err) => {
+                // the segment may end in part of a record, start a new one
+                if let Some(segment) = segments.remove(&route.topic) {
+                    warn!("abandoning archive segment {}", segment.path.display());
+                }
+                let msg = err.to_string();
+                records.iter().map(|_| Err(anyhow!("{}", msg))).collect()
+            }
+        }
+    }
+}
+
+/// a request read from a segment
+pub struct Archived {
+    pub request: DecodedRequest,
+    /// the buffer message's headers, like its encoding
+    pub headers: Vec<(String, String)>,
+}
+
+/// Reads the requests in a segment, in order, by its file name.
+pub struct SegmentReader {
+    reader: Box<dyn BufRead>,
+    format: ArchiveFormat,
+}
+
+impl SegmentReader {
+    pub fn open(path: &Path) -> anyhow::Result<SegmentReader> {
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let (name, compressed) = match name.strip_suffix(".zst") {
+            Some(name) => (name.to_owned(), true),
+            None => (name, false),
+        };
+        let format = if name.ends_with(".jsonl") {
+            ArchiveFormat::Jsonl
+        } else if name.ends_with(".capnp") {
+            ArchiveFormat::Capnp
+        } else {
+            return Err(anyhow!("{} is not an archive segment", path.display()));
+        };
+        let file = File::open(path)?;
+        let reader: Box<dyn BufRead> = if compressed {
+            Box::new(BufReader::new(zstd::Decoder::new(file)?))
+        } else {
+            Box::new(BufReader::new(file))
+        };
+        Ok(SegmentReader { reader, format })
+    }
+
+    fn read_jsonl(&mut self) -> anyhow::Result<Option<Archived>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let entry: Value = serde_json::from_str(&line)?;
+        let body = match (entry.get("body").and_then(Value::as_str), entry.get("body_base64").and_then(Value::as_str)) {
+            (Some(body), _) => body.as_bytes().to_vec(),
+            (None, Some(body)) => STANDARD.decode(body)?,
+            (None, None) => return Err(anyhow!("archive entry has no body")),
+        };
+        let headers = match entry.get("headers") {
+            Some(Value::Object(headers)) => headers.clone(),
+            _ => Map::new(),
+        };
+        let buffer_headers = match entry.get("buffer_headers") {
+            Some(headers) => serde_json::from_value(headers.clone())?,
+            None => Vec::new(),
+        };
+        Ok(Some(Archived {
+            request: DecodedRequest {
+                body,
+                headers,
+                body_ref: None,
+            },
+            headers: buffer_headers,
+        }))
+    }
+
+    fn read_capnp(&mut self) -> anyhow::Result<Option<Archived>> {
+        let mut len = [0; 4];
+        match self.reader.read_exact(&mut len) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            r => r?,
+        }
+        let mut headers_len = [0; 2];
+        self.reader.read_exact(&mut headers_len)?;
+        let mut headers = vec![0; u16::from_be_bytes(headers_len) as usize];
+        self.reader.read_exact(&mut headers)?;
+        let mut message = vec![0; u32::from_be_bytes(len) as usize];
+        self.reader.read_exact(&mut message)?;
+        Ok(Some(Archived {
+            request: crate::decode_request(Some(&message))?,
+            headers: serde_json::from_slice(&headers)?,
+        }))
+    }
+}
+
+impl Iterator for SegmentReader {
+    type Item = anyhow::Result<Archived>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let r_next = match self.format {
+            ArchiveFormat::Jsonl => self.read_jsonl(),
+            ArchiveFormat::Capnp => self.read_capnp(),
+        };
+        r_next.transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse;
+    use crate::encoding::ENCODING_HEADER;
+
+    fn record(offset: i64) -> Record {
+        let mut headers = Map::new();
+        headers.insert("content-type".to_owned(), json!("application/json"));
+        Record {
+            topic: "t".to_owned(),
+            partition: 0,
+            offset,
+            request: DecodedRequest {
+                body: b"{\"a\": 1}".to_vec(),
+                headers,
+                body_ref: None,
+            },
+            headers: vec![
+                (ENCODING_HEADER.to_owned(), "json/1".to_owned()),
+                ("ce_id".to_owned(), "abc".to_owned()),
+            ],
+            received_at: 0.0,
+            error: None,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn round_trip() {
+        let route = parse(r#"(("/t" . ((sink . "archive") (topic . "t"))))"#).unwrap().0.remove("/t").unwrap();
+        for (format, zstd_level) in [(ArchiveFormat::Jsonl, None), (ArchiveFormat::Capnp, Some(3))] {
+            let dir = std::env::temp_dir().join(format!("archive-test-{}-{}", std::process::id(), format.extension()));
+            let archive = Archive::new(&dir, format, 1 << 20, Duration::from_secs(3600), zstd_level);
+            let records = [record(7), record(8)];
+            for outcome in archive.push_batch(&route, &records).await {
+                assert_eq!(outcome.unwrap(), Outcome::Enqueued);
+            }
+            let segments = archive.segments.into_inner();
+            let segment = segments.into_values().next().unwrap();
+            let path = segment.path.clone();
+            close(segment).unwrap();
+
+            let archived: Vec<Archived> = SegmentReader::open(&path).unwrap().map(Result::unwrap).collect();
+            assert_eq!(archived.len(), 2, "{:?}", format);
+            for (archived, record) in archived.iter().zip(&records) {
+                assert_eq!(archived.request.body, record.request.body);
+                assert_eq!(archived.request.headers, record.request.headers);
+                assert_eq!(archived.headers, record.headers);
+            }
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
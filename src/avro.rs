@@ -0,0 +1,414 @@
+//! Avro encoding, for consumers with Confluent tooling.  The producer
+//! registers the BufferedRequest schema in a schema registry, under the
+//! subject `<topic>-value`, and each message is a zero byte, the schema id
+//! as a big-endian u32, and the Avro datum.  The consumer looks up the
+//! schema each message was written with, and reads it as the current schema.
+
+use crate::{insert_header, DecodedRequest};
+use anyhow::{anyhow, Context};
+use apache_avro::schema_compatibility::SchemaCompatibility;
+use apache_avro::types::Value as AvroValue;
+use apache_avro::{from_avro_datum, to_avro_datum, Schema};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::header::{ACCEPT, CONTENT_TYPE};
+use hyper::{Method, Request, StatusCode};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use lazy_static::lazy_static;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::*;
+
+/// Fields added later need defaults, so this schema can read messages
+/// written before them, and the registry accepts it as backward compatible.
+const SCHEMA_JSON: &str = r#"{
+  "type": "record",
+  "name": "BufferedRequest",
+  "namespace": "kafka_buffer",
+  "fields": [
+    {"name": "body", "type": "bytes"},
+    {"name": "headers", "default": [], "type": {"type": "array", "items": {
+      "type": "record",
+      "name": "Header",
+      "fields": [
+        {"name": "name", "type": "string"},
+        {"name": "value", "type": "bytes"}
+      ]
+    }}},
+    {"name": "body_ref", "type": ["null", "string"], "default": null}
+  ]
+}"#;
+
+lazy_static! {
+    static ref SCHEMA: Schema = Schema::parse_str(SCHEMA_JSON).expect("valid avro schema");
+}
+
+/// the first byte of Confluent's framing
+const MAGIC: u8 = 0;
+
+const REGISTRY_CONTENT_TYPE: &str = "application/vnd.schemaregistry.v1+json";
+
+/// how long to wait for the registry, before calling it unavailable
+const REGISTRY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// the registry didn't answer, or answered 5xx, so try again later
+    Unavailable(anyhow::Error),
+    /// the message can't be read as a BufferedRequest
+    Invalid(anyhow::Error),
+}
+
+impl DecodeError {
+    pub fn is_transient(&self) -> bool {
+        matches!(self, DecodeError::Unavailable(_))
+    }
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Unavailable(err) => write!(f, "schema registry unavailable: {}", err),
+            DecodeError::Invalid(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// the request, framed with the id SCHEMA was registered as
+pub fn encode(schema_id: u32, request: &DecodedRequest) -> Vec<u8> {
+    let headers = request
+        .header_values()
+        .map(|(name, value)| {
+            AvroValue::Record(vec![
+                ("name".to_string(), AvroValue::String(name.to_owned())),
+                ("value".to_string(), AvroValue::Bytes(value.as_bytes().to_vec())),
+            ])
+        })
+        .collect();
+    let datum = to_avro_datum(
+        &SCHEMA,
+        AvroValue::Record(vec![
+            ("body".to_string(), AvroValue::Bytes(request.body.clone())),
+            ("headers".to_string(), AvroValue::Array(headers)),
+            (
+                "body_ref".to_string(),
+                match &request.body_ref {
+                    None => AvroValue::Union(0, Box::new(AvroValue::Null)),
+                    Some(body_ref) => AvroValue::Union(1, Box::new(AvroValue::String(body_ref.clone()))),
+                },
+            ),
+        ]),
+    )
+    .expect("request matches SCHEMA");
+    let mut ret = Vec::with_capacity(5 + datum.len());
+    ret.push(MAGIC);
+    ret.extend(schema_id.to_be_bytes());
+    ret.extend(datum);
+    ret
+}
+
+/// the schema id from a message's framing, and the datum after it
+fn unframe(bytes: &[u8]) -> anyhow::Result<(u32, &[u8])> {
+    match bytes {
+        [MAGIC, a, b, c, d, datum @ ..] => Ok((u32::from_be_bytes([*a, *b, *c, *d]), datum)),
+        _ => Err(anyhow!("avro message without the magic byte and schema id")),
+    }
+}
+
+/// a datum written with `writer`, read as SCHEMA
+fn decode_datum(writer: &Schema, mut datum: &[u8]) -> anyhow::Result<DecodedRequest> {
+    let AvroValue::Record(fields) = from_avro_datum(writer, &mut datum, Some(&SCHEMA))? else {
+        return Err(anyhow!("avro datum is not a record"));
+    };
+    let mut request = DecodedRequest {
+        body: Vec::new(),
+        headers: Map::new(),
+        body_ref: None,
+    };
+    for (field, value) in fields {
+        match (field.as_str(), value) {
+            ("body", AvroValue::Bytes(body)) => request.body = body,
+            ("body_ref", AvroValue::Union(_, body_ref)) => {
+                if let AvroValue::String(body_ref) = *body_ref {
+                    request.body_ref = Some(body_ref);
+                }
+            }
+            ("headers", AvroValue::Array(headers)) => {
+                for header in headers {
+                    let AvroValue::Record(hh) = header else {
+                        return Err(anyhow!("avro header is not a record"));
+                    };
+                    let (mut name, mut value) = (None, None);
+                    for (field, v) in hh {
+                        match (field.as_str(), v) {
+                            ("name", AvroValue::String(s)) => name = Some(s),
+                            ("value", AvroValue::Bytes(b)) => value = Some(String::from_utf8(b)?),
+                            _ => (),
+                        }
+                    }
+                    if let (Some(name), Some(value)) = (name, value) {
+                        insert_header(&mut request.headers, name, value);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(request)
+}
+
+/// a Confluent-compatible schema registry, and the schemas read from it
+pub struct SchemaRegistry {
+    url: String,
+    client: Client<HttpConnector, Full<Bytes>>,
+    schemas: Mutex<HashMap<u32, Arc<Schema>>>,
+}
+
+impl SchemaRegistry {
+    pub fn new(url: &str) -> SchemaRegistry {
+        SchemaRegistry {
+            url: url.trim_end_matches('/').to_owned(),
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            schemas: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// SCHEMA_REGISTRY_URL, or a registry on localhost
+    pub fn from_env() -> SchemaRegistry {
+        SchemaRegistry::new(&env::var("SCHEMA_REGISTRY_URL").unwrap_or("http://localhost:8081".to_string()))
+    }
+
+    async fn call(&self, method: Method, path: &str, body: Option<Value>) -> anyhow::Result<(StatusCode, Value)> {
+        let request = Request::builder()
+            .method(method)
+            .uri(format!("{}{}", self.url, path))
+            .header(ACCEPT, REGISTRY_CONTENT_TYPE)
+            .header(CONTENT_TYPE, REGISTRY_CONTENT_TYPE)
+            .body(Full::new(Bytes::from(body.map(|b| b.to_string()).unwrap_or_default())))?;
+        let response = tokio::time::timeout(REGISTRY_TIMEOUT, self.client.request(request))
+            .await
+            .map_err(|_| anyhow!("no answer within {:?}", REGISTRY_TIMEOUT))?
+            .context("schema registry")?;
+        let status = response.status();
+        let bytes = response.into_body().collect().await?.to_bytes();
+        let json = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+        Ok((status, json))
+    }
+
+    /// Register SCHEMA for a topic's values, after checking that it is
+    /// compatible with the latest version there, and return its id.
+    pub async fn register(&self, topic: &str) -> anyhow::Result<u32> {
+        let subject = format!("{}-value", topic);
+        let body = json!({"schema": SCHEMA_JSON});
+        let path = format!("/compatibility/subjects/{}/versions/latest", subject);
+        match self.call(Method::POST, &path, Some(body.clone())).await? {
+            (StatusCode::OK, json) if json["is_compatible"] == json!(true) => (),
+            (StatusCode::OK, json) => {
+                return Err(anyhow!("avro schema is incompatible with the latest version of {}: {}", subject, json))
+            }
+            // first version of this subject
+            (StatusCode::NOT_FOUND, _) => (),
+            (status, json) => return Err(anyhow!("checking compatibility of {}: {} {}", subject, status, json)),
+        }
+        match self.call(Method::POST, &format!("/subjects/{}/versions", subject), Some(body)).await? {
+            (StatusCode::OK, json) => {
+                let id = json["id"].as_u64().and_then(|id| u32::try_from(id).ok());
+                let id = id.ok_or(anyhow!("registering {}: no id in {}", subject, json))?;
+                info!("registered avro schema subject={} id={}", subject, id);
+                Ok(id)
+            }
+            (status, json) => Err(anyhow!("registering {}: {} {}", subject, status, json)),
+        }
+    }
+
+    /// a writer's schema, fetched once, which SCHEMA must be able to read
+    async fn schema(&self, id: u32) -> Result<Arc<Schema>, DecodeError> {
+        if let Some(schema) = self.schemas.lock().unwrap().get(&id) {
+            return Ok(schema.clone());
+        }
+        let path = format!("/schemas/ids/{}", id);
+        let schema = match self.call(Method::GET, &path, None).await.map_err(DecodeError::Unavailable)? {
+            (StatusCode::OK, json) => {
+                let text = json["schema"]
+                    .as_str()
+                    .ok_or_else(|| DecodeError::Invalid(anyhow!("schema {}: no schema in {}", id, json)))?;
+                Schema::parse_str(text).map_err(|err| DecodeError::Invalid(anyhow!("schema {}: {}", id, err)))?
+            }
+            (status, json) if status.is_server_error() => {
+                return Err(DecodeError::Unavailable(anyhow!("fetching schema {}: {} {}", id, status, json)))
+            }
+            (status, json) => return Err(DecodeError::Invalid(anyhow!("fetching schema {}: {} {}", id, status, json))),
+        };
+        if SchemaCompatibility::can_read(&schema, &SCHEMA).is_err() {
+            return Err(DecodeError::Invalid(anyhow!("schema {} can't be read as BufferedRequest", id)));
+        }
+        let schema = Arc::new(schema);
+        self.schemas.lock().unwrap().insert(id, schema.clone());
+        Ok(schema)
+    }
+
+    pub async fn decode(&self, bytes: &[u8]) -> Result<DecodedRequest, DecodeError> {
+        let (id, datum) = unframe(bytes).map_err(DecodeError::Invalid)?;
+        let writer = self.schema(id).await?;
+        decode_datum(&writer, datum).map_err(DecodeError::Invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::Incoming;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::Response;
+    use hyper_util::rt::TokioIo;
+    use std::convert::Infallible;
+    use tokio::net::TcpListener;
+
+    /// the registry endpoints SchemaRegistry uses, in memory, checking
+    /// compatibility as the registry's BACKWARD level does
+    #[derive(Default)]
+    struct StandIn {
+        /// id - 1 => schema text
+        schemas: Vec<String>,
+        subjects: HashMap<String, Vec<u32>>,
+    }
+
+    impl StandIn {
+        fn latest(&self, subject: &str) -> Option<&str> {
+            let id = self.subjects.get(subject)?.last()?;
+            Some(&self.schemas[*id as usize - 1])
+        }
+
+        /// whether `reader` can read what the subject's latest version wrote
+        fn compatible(&self, subject: &str, reader: &str) -> bool {
+            match (self.latest(subject).map(Schema::parse_str), Schema::parse_str(reader)) {
+                (Some(Ok(writer)), Ok(reader)) => SchemaCompatibility::can_read(&writer, &reader).is_ok(),
+                _ => false,
+            }
+        }
+
+        fn handle(&mut self, method: &Method, path: &str, body: &Value) -> (StatusCode, Value) {
+            let schema = body["schema"].as_str().unwrap_or_default();
+            if let Some(id) = path.strip_prefix("/schemas/ids/").and_then(|id| id.parse::<usize>().ok()) {
+                return match self.schemas.get(id.wrapping_sub(1)) {
+                    Some(schema) if *method == Method::GET => (StatusCode::OK, json!({"schema": schema})),
+                    _ => (StatusCode::NOT_FOUND, json!({"error_code": 40403})),
+                };
+            }
+            if let Some(subject) = path
+                .strip_prefix("/compatibility/subjects/")
+                .and_then(|p| p.strip_suffix("/versions/latest"))
+            {
+                return match self.latest(subject) {
+                    None => (StatusCode::NOT_FOUND, json!({"error_code": 40401})),
+                    Some(_) => (StatusCode::OK, json!({"is_compatible": self.compatible(subject, schema)})),
+                };
+            }
+            if let Some(subject) = path.strip_prefix("/subjects/").and_then(|p| p.strip_suffix("/versions")) {
+                if self.latest(subject).is_some() && !self.compatible(subject, schema) {
+                    return (StatusCode::CONFLICT, json!({"error_code": 409}));
+                }
+                if !self.schemas.iter().any(|s| s == schema) {
+                    self.schemas.push(schema.to_owned());
+                }
+                let id = self.schemas.iter().position(|s| s == schema).unwrap() as u32 + 1;
+                self.subjects.entry(subject.to_owned()).or_default().push(id);
+                return (StatusCode::OK, json!({"id": id}));
+            }
+            (StatusCode::NOT_FOUND, json!({"error_code": 404}))
+        }
+    }
+
+    async fn serve(stand_in: StandIn) -> String {
+        let stand_in = Arc::new(Mutex::new(stand_in));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let stand_in = stand_in.clone();
+                let service = service_fn(move |request: Request<Incoming>| {
+                    let stand_in = stand_in.clone();
+                    async move {
+                        let (method, path) = (request.method().clone(), request.uri().path().to_owned());
+                        let bytes = request.into_body().collect().await.unwrap().to_bytes();
+                        let body = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+                        let (status, json) = stand_in.lock().unwrap().handle(&method, &path, &body);
+                        let response = Response::builder().status(status).body(Full::new(Bytes::from(json.to_string())));
+                        Ok::<_, Infallible>(response.unwrap())
+                    }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        url
+    }
+
+    fn request() -> DecodedRequest {
+        let mut headers = Map::new();
+        insert_header(&mut headers, "x-event".to_string(), "created".to_string());
+        insert_header(&mut headers, "x-event".to_string(), "updated".to_string());
+        DecodedRequest {
+            body: b"{\"id\": 1}".to_vec(),
+            headers,
+            body_ref: Some("file:///tmp/bodies/t/1".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn register_and_decode() {
+        let registry = SchemaRegistry::new(&serve(StandIn::default()).await);
+        let id = registry.register("t").await.unwrap();
+        // registering again finds the same schema
+        assert_eq!(registry.register("t").await.unwrap(), id);
+        let decoded = registry.decode(&encode(id, &request())).await.unwrap();
+        assert_eq!(decoded.body, request().body);
+        assert_eq!(decoded.headers, request().headers);
+        assert_eq!(decoded.body_ref, request().body_ref);
+    }
+
+    #[tokio::test]
+    async fn register_incompatible() {
+        let mut stand_in = StandIn::default();
+        // a body which SCHEMA can't read
+        let old = r#"{"type": "record", "name": "BufferedRequest", "namespace": "kafka_buffer",
+                      "fields": [{"name": "body", "type": "int"}]}"#;
+        stand_in.handle(&Method::POST, "/subjects/t-value/versions", &json!({"schema": old}));
+        let registry = SchemaRegistry::new(&serve(stand_in).await);
+        let err = registry.register("t").await.unwrap_err();
+        assert!(err.to_string().contains("incompatible"), "{}", err);
+        // other topics are unaffected
+        registry.register("u").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn decode_unknown_schema_is_invalid() {
+        let registry = SchemaRegistry::new(&serve(StandIn::default()).await);
+        let err = registry.decode(&encode(7, &request())).await.unwrap_err();
+        assert!(!err.is_transient(), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn decode_without_registry_is_transient() {
+        // bound, then closed, so nothing is listening
+        let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let registry = SchemaRegistry::new(&format!("http://{}", addr));
+        let err = registry.decode(&encode(1, &request())).await.unwrap_err();
+        assert!(err.is_transient(), "{}", err);
+    }
+
+    #[test]
+    fn unframe_checks_magic_and_length() {
+        assert_eq!(unframe(&[0, 0, 0, 1, 2, 9]).unwrap(), (258, &[9u8][..]));
+        assert_eq!(unframe(&[0, 0, 0, 0, 1]).unwrap(), (1, &[][..]));
+        assert!(unframe(&[]).is_err());
+        assert!(unframe(&[0, 0, 0, 1]).is_err());
+        assert!(unframe(&[1, 0, 0, 0, 1, 9]).is_err());
+    }
+}
@@ -1,21 +1,31 @@
 use kafka_buffer::config::*;
-use kafka_buffer::observability::{self, hist_time_since};
-use rdkafka::message::BorrowedMessage;
-use kafka_buffer::buffered_http_request_capnp::buffered_request;
+use kafka_buffer::archive::{Archive, ArchiveFormat};
+use kafka_buffer::avro::{self, SchemaRegistry};
+use kafka_buffer::observability;
+use kafka_buffer::jid::DedupeWindow;
+use kafka_buffer::bullmq::BullMq;
+use kafka_buffer::celery::Celery;
+use kafka_buffer::faktory::Faktory;
+use kafka_buffer::job::{epoch_s, Sidekiq};
+use kafka_buffer::buffer::{consumer_from_env, BufferConsumer, Message};
+use kafka_buffer::offsets::RedisOffsets;
+use kafka_buffer::relay::HttpRelay;
+use kafka_buffer::sink::{JobSink, Outcome, Record};
+use kafka_buffer::stream::RedisStream;
+use kafka_buffer::claimcheck::{ClaimCheck, FetchError, BODY_REF_HEADER};
+use kafka_buffer::cloudevents;
+use kafka_buffer::encoding::{Encoding, ENCODING_HEADER};
 
-use serde_json::map::Map;
 use anyhow::{anyhow, Context};
-use prometheus::{self, register_histogram, register_int_counter, Histogram, IntCounter};
+use prometheus::{self, register_int_counter, IntCounter};
+use std::collections::HashMap;
 use std::env;
-use std::time::Instant;
+use std::fmt::Display;
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 use tracing::*;
-use capnp::message::ReaderOptions;
-
-use rdkafka::config::ClientConfig;
-use rdkafka::consumer::stream_consumer::StreamConsumer;
-use rdkafka::consumer::{CommitMode, Consumer};
-use rdkafka::Message;
-use sidekiq::{create_redis_pool, Client, Job, JobOpts};
 
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
@@ -30,61 +40,303 @@ lazy_static! {
     static ref KAFKA_MESSAGE_RECEIVED: IntCounter =
         register_int_counter!("kafka_message_received", "number of messages read").unwrap();
     static ref JOBS_WRITTEN: IntCounter =
-        register_int_counter!("jobs_written", "number of Sidekiq jobs written to Redis").unwrap();
-    static ref REDIS_DURATION_S: Histogram =
-        register_histogram!("redis_duration_s", "duration of writes to Redis queues").unwrap();
+        register_int_counter!("jobs_written", "number of jobs written to a queue").unwrap();
+    static ref JOBS_SCHEDULED: IntCounter =
+        register_int_counter!("jobs_scheduled", "number of jobs written to be run later").unwrap();
+    static ref DEAD_LETTERED: IntCounter =
+        register_int_counter!("dead_lettered", "number of requests which could not become jobs").unwrap();
+    static ref DUPLICATES_SKIPPED: IntCounter =
+        register_int_counter!("duplicates_skipped", "number of messages skipped because they were already written").unwrap();
 }
 
-async fn write_sidekiq_job<'a>(
-    consumer: &StreamConsumer,
-    sidekiq_client: &Client,
-    route: &Route,
-    message: BorrowedMessage<'a>,
+const GROUP_ID: &str = "kafka-buffer";
+const CLAIM_CHECK_SWEEP_INTERVAL: Duration = Duration::from_secs(3600);
+/// tries of a store or registry which doesn't answer, before reading the
+/// message again later
+const TRANSIENT_TRIES: u32 = 3;
+const TRANSIENT_BACKOFF: Duration = Duration::from_millis(200);
+
+type Sinks = HashMap<SinkKind, Box<dyn JobSink>>;
+
+/// Read more messages, until the batch is full or none arrive within `linger`.
+async fn fill_batch(
+    consumer: &dyn BufferConsumer,
+    batch: &mut Vec<Message>,
+    batch_size: usize,
+    linger: Duration,
 ) -> anyhow::Result<()> {
-    let job_opts = JobOpts {
-        queue: route.queue.clone(),
-        ..Default::default()
-    };
+    while batch.len() < batch_size {
+        match tokio::time::timeout(linger, consumer.recv()).await {
+            Err(_elapsed) => break,
+            Ok(r_message) => batch.push(r_message?),
+        }
+    }
+    Ok(())
+}
 
-    KAFKA_MESSAGE_RECEIVED.inc();
-    match decode_capnp_message(message.payload()) {
-        Err(err) => {
-            error!("skipping topic={} offset={} could not decode payload: {}", message.topic(), message.offset(), err);
-            Ok(())
+/// Call `f` until it succeeds, fails in a way which isn't `transient`, or
+/// runs out of tries.
+async fn retry<T, E: Display, Fut: Future<Output = Result<T, E>>>(
+    what: &str,
+    transient: impl Fn(&E) -> bool,
+    mut f: impl FnMut() -> Fut,
+) -> Result<T, E> {
+    let mut backoff = TRANSIENT_BACKOFF;
+    let mut tries = 1;
+    loop {
+        match f().await {
+            Err(err) if transient(&err) && tries < TRANSIENT_TRIES => {
+                warn!("{} failed, trying again: {}", what, err);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                tries += 1;
+            }
+            r => return r,
         }
-        Ok(job_args) => {
-            debug!("received topic={} job_args={:?}", message.topic(), job_args);
-            let job = Job {
-                class: route.job_class.clone(),
-                args: job_args,
-                retry: job_opts.retry,
-                queue: job_opts.queue.clone(),
-                jid: job_opts.jid.clone(),
-                created_at: job_opts.created_at,
-                enqueued_at: job_opts.enqueued_at,
-            };
-            let start = Instant::now();
-            let r_push = sidekiq_client.push_async(job).await;
-            hist_time_since(&REDIS_DURATION_S, start);
-            match r_push {
-                Ok(_) => {
-                    JOBS_WRITTEN.inc();
-                    consumer.commit_consumer_state(CommitMode::Async)?;
-                    Ok(())
+    }
+}
+
+/// Write jobs for a batch of messages, grouped by route, then commit.  A
+/// partition is committed only up to its first failed message, and read
+/// again from there.
+async fn write_jobs(
+    consumer: &dyn BufferConsumer,
+    sinks: &Sinks,
+    registry: &SchemaRegistry,
+    claim_check: Option<&ClaimCheck>,
+    topics_map: &HashMap<String, Route>,
+    messages: Vec<Message>,
+) -> anyhow::Result<()> {
+    let mut batches: Vec<(&Route, Vec<Record>)> = Vec::new();
+    let mut sought: HashMap<(String, i32), i64> = HashMap::new();
+    // the offset to commit for each partition, if every push succeeds
+    let mut next_offsets: HashMap<(String, i32), i64> = HashMap::new();
+    // the first offset of each partition to read again
+    let mut failed: HashMap<(String, i32), i64> = HashMap::new();
+    for message in messages {
+        KAFKA_MESSAGE_RECEIVED.inc();
+        let route = topics_map.get(&message.topic).expect("message came from a topic we subscribed to");
+        let sink = &sinks[&route.sink];
+        let coordinates = (message.topic.clone(), message.partition);
+        // can't seek during the rebalance callback, so wait for the first message
+        if consumer.take_assigned(&message.topic, message.partition) {
+            match sink.stored_offset(&message.topic, message.partition).await {
+                Ok(Some(next)) if next > message.offset => {
+                    info!("seeking topic={} partition={} from offset={} to stored offset={}",
+                          message.topic, message.partition, message.offset, next);
+                    match consumer.seek(&message.topic, message.partition, next).await {
+                        Ok(()) => {
+                            sought.insert(coordinates.clone(), next);
+                        }
+                        // the sink skips offsets already written, so carry on
+                        Err(err) => warn!("could not seek to stored offset: {}", err),
+                    }
+                }
+                Ok(_) => (),
+                Err(err) => warn!("could not read stored offset: {}", err),
+            }
+        }
+        if sought.get(&coordinates).is_some_and(|next| message.offset < *next) {
+            DUPLICATES_SKIPPED.inc();
+            continue;
+        }
+        if failed.contains_key(&coordinates) {
+            continue;
+        }
+        next_offsets.insert(coordinates.clone(), message.offset + 1);
+        let r_request = match Encoding::from_header(message.header(ENCODING_HEADER)) {
+            Ok(Encoding::Avro { .. }) => {
+                retry("schema registry", avro::DecodeError::is_transient, || registry.decode(&message.payload)).await
+            }
+            r_encoding => r_encoding.and_then(|e| e.decode(&message.payload)).map_err(avro::DecodeError::Invalid),
+        };
+        match r_request {
+            Err(err) if err.is_transient() => {
+                error!("topic={} offset={} reading again later: {}", message.topic, message.offset, err);
+                failed.insert(coordinates, message.offset);
+            }
+            Err(err) => {
+                error!("skipping topic={} offset={} could not decode payload: {}", message.topic, message.offset, err);
+            }
+            Ok(mut request) => {
+                // CloudEvent attributes reach jobs as ce-* headers
+                request.headers.extend(cloudevents::job_headers(&message.headers));
+                let mut record_error = None;
+                if let Some(body_ref) = request.body_ref.take() {
+                    match (route.pass_body_ref, claim_check) {
+                        (true, _) => {
+                            request.headers.insert(BODY_REF_HEADER.to_string(), body_ref.into());
+                        }
+                        (false, Some(claim_check)) => {
+                            match retry("claim check fetch", FetchError::is_transient, || claim_check.fetch(&body_ref)).await {
+                                Ok(body) => request.body = body,
+                                Err(FetchError::Gone(reason)) => {
+                                    error!("topic={} offset={} dead lettering: {}", message.topic, message.offset, reason);
+                                    request.headers.insert(BODY_REF_HEADER.to_string(), body_ref.into());
+                                    record_error = Some(format!("claim-checked body is gone: {}", reason));
+                                }
+                                Err(err) => {
+                                    error!("topic={} offset={} reading again later: {}", message.topic, message.offset, err);
+                                    failed.insert(coordinates, message.offset);
+                                    continue;
+                                }
+                            }
+                        }
+                        (false, None) => {
+                            // until CLAIM_CHECK_URL is set, rather than drop the request
+                            error!("topic={} offset={} has body_ref {}, and CLAIM_CHECK_URL isn't set", message.topic, message.offset, body_ref);
+                            failed.insert(coordinates, message.offset);
+                            continue;
+                        }
+                    }
+                }
+                debug!("received topic={} request={:?}", message.topic, request);
+                let record = Record {
+                    topic: message.topic,
+                    partition: message.partition,
+                    offset: message.offset,
+                    request,
+                    headers: message.headers,
+                    received_at: message.timestamp_ms.map(|ms| ms as f64 / 1000.0).unwrap_or_else(epoch_s),
+                    error: record_error,
+                };
+                match batches.iter_mut().find(|(r, _)| r.topic == route.topic) {
+                    Some((_, records)) => records.push(record),
+                    None => batches.push((route, vec![record])),
                 }
+            }
+        }
+    }
+
+    for (route, records) in batches {
+        let results = sinks[&route.sink].push_batch(route, &records).await;
+        for (record, result) in records.iter().zip(results) {
+            match result {
+                Ok(outcome) => match outcome {
+                    Outcome::Enqueued => JOBS_WRITTEN.inc(),
+                    Outcome::Scheduled => JOBS_SCHEDULED.inc(),
+                    Outcome::DeadLettered => DEAD_LETTERED.inc(),
+                    Outcome::Duplicate => DUPLICATES_SKIPPED.inc(),
+                },
                 Err(err) => {
-                    error!("Sidekiq push failed: {}", err);
-                    Ok(()) // no commit, try again on next recv?
+                    error!("push failed topic={} offset={}: {}", record.topic, record.offset, err);
+                    let first = failed.entry((record.topic.clone(), record.partition)).or_insert(record.offset);
+                    *first = (*first).min(record.offset);
                 }
             }
         }
     }
+    if next_offsets.is_empty() {
+        return Ok(());
+    }
+    let mut commits = Vec::with_capacity(next_offsets.len());
+    for ((topic, partition), next) in next_offsets {
+        let next = match failed.get(&(topic.clone(), partition)) {
+            None => next,
+            Some(first) => {
+                // later messages may be written again, as with any retry
+                if let Err(err) = consumer.seek(&topic, partition, *first).await {
+                    warn!("could not seek back to failed offset: {}", err);
+                }
+                *first
+            }
+        };
+        commits.push((topic, partition, next));
+    }
+    consumer.commit(&commits).await?;
+    Ok(())
+}
+
+async fn make_sink(kind: SinkKind, redis_conn: &redis::aio::MultiplexedConnection) -> anyhow::Result<Box<dyn JobSink>> {
+    match kind {
+        SinkKind::Sidekiq => {
+            // OFFSET_STORAGE=redis pushes jobs and advances offsets atomically, for effectively-once delivery
+            let redis_offsets = match env::var("OFFSET_STORAGE").as_deref() {
+                Ok("redis") => Some(RedisOffsets::new(redis_conn.clone(), GROUP_ID)),
+                Ok("kafka") | Err(_) => None,
+                Ok(other) => return Err(anyhow!("OFFSET_STORAGE must be kafka or redis, got {}", other)),
+            };
+            // DEDUPE_WINDOW_S skips jobs whose jid was written within that many
+            // seconds.  0 turns it off, as redis won't expire a key after 0s
+            let dedupe = env::var("DEDUPE_WINDOW_S")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .filter(|window_s| *window_s > 0)
+                .map(|window_s| DedupeWindow::new(redis_conn.clone(), window_s));
+            Ok(Box::new(Sidekiq::new(redis_conn.clone(), redis_offsets, dedupe)))
+        }
+        SinkKind::Celery => {
+            // CELERY_BROKER_URL, if the broker isn't the Sidekiq Redis
+            let conn = match env::var("CELERY_BROKER_URL") {
+                Ok(url) => redis::Client::open(url)?
+                    .get_multiplexed_async_connection()
+                    .await
+                    .context("celery broker connection")?,
+                Err(_) => redis_conn.clone(),
+            };
+            Ok(Box::new(Celery::new(conn)))
+        }
+        SinkKind::BullMq => {
+            // BULLMQ_REDIS_URL, if BullMQ doesn't share the Sidekiq Redis
+            let conn = match env::var("BULLMQ_REDIS_URL") {
+                Ok(url) => redis::Client::open(url)?
+                    .get_multiplexed_async_connection()
+                    .await
+                    .context("bullmq redis connection")?,
+                Err(_) => redis_conn.clone(),
+            };
+            let prefix = env::var("BULLMQ_PREFIX").unwrap_or("bull".to_string());
+            Ok(Box::new(BullMq::new(conn, &prefix)))
+        }
+        SinkKind::Faktory => {
+            let url = env::var("FAKTORY_URL").unwrap_or("tcp://localhost:7419".to_string());
+            Ok(Box::new(Faktory::new(url.parse().context("FAKTORY_URL")?)))
+        }
+        SinkKind::RedisStream => {
+            // STREAM_REDIS_URL, if the streams aren't in the Sidekiq Redis
+            let conn = match env::var("STREAM_REDIS_URL") {
+                Ok(url) => redis::Client::open(url)?
+                    .get_multiplexed_async_connection()
+                    .await
+                    .context("stream redis connection")?,
+                Err(_) => redis_conn.clone(),
+            };
+            Ok(Box::new(RedisStream::new(conn)))
+        }
+        SinkKind::Archive => {
+            let dir = env::var("ARCHIVE_DIR").unwrap_or("archive".to_string());
+            let format = match env::var("ARCHIVE_FORMAT").as_deref() {
+                Ok("jsonl") | Err(_) => ArchiveFormat::Jsonl,
+                Ok("capnp") => ArchiveFormat::Capnp,
+                Ok(other) => return Err(anyhow!("ARCHIVE_FORMAT must be jsonl or capnp, got {}", other)),
+            };
+            let var = |name: &str, default: u64| env::var(name).ok().and_then(|s| s.parse().ok()).unwrap_or(default);
+            // ARCHIVE_ZSTD_LEVEL compresses segments, 3 is zstd's default
+            let zstd_level = env::var("ARCHIVE_ZSTD_LEVEL").ok().and_then(|s| s.parse().ok());
+            Ok(Box::new(Archive::new(
+                Path::new(&dir),
+                format,
+                var("ARCHIVE_MAX_BYTES", 128 << 20),
+                Duration::from_secs(var("ARCHIVE_MAX_AGE_S", 3600)),
+                zstd_level,
+            )))
+        }
+        SinkKind::Http => {
+            let var = |name: &str, default: u64| env::var(name).ok().and_then(|s| s.parse().ok()).unwrap_or(default);
+            // HTTP_CONCURRENCY limits requests in flight to all upstreams
+            Ok(Box::new(HttpRelay::new(
+                var("HTTP_CONCURRENCY", 16) as usize,
+                Duration::from_millis(var("HTTP_TIMEOUT_MS", 10_000)),
+                var("HTTP_RETRIES", 5) as u32,
+                Duration::from_millis(var("HTTP_BACKOFF_MS", 100)),
+            )))
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     observability::init()?;
-    let kafka_url = env::var("KAFKA_URL").unwrap_or("localhost:9092".to_string());
     let metrics_address = env::var("METRICS_ADDRESS")
         .ok()
         .and_then(|s| s.parse().ok())
@@ -92,21 +344,54 @@ async fn main() -> anyhow::Result<()> {
     let config_file_name = env::var("CONFIG_FILE").unwrap_or(DEFAULT_CONFIG_FILE.to_string());
     let topics_map = parse_from_file(&config_file_name).by_topic();
 
-    // Create the `StreamConsumer`, to receive the messages from the topic in form of a `Stream`.
-    let consumer: StreamConsumer = ClientConfig::new()
-        .set("group.id", "kafka-buffer")
-        .set("bootstrap.servers", &kafka_url)
-        .set("enable.partition.eof", "false")
-        .set("session.timeout.ms", "6000")
-        .set("enable.auto.commit", "false")
-        .create()
-        .context("kafka consumer")?;
     let topics: Vec<&str> = topics_map.keys().map(|x| &**x).collect();
     info!("subscribing to {:?}", topics);
-    consumer.subscribe(&topics)?;
+    // Kafka, unless BUFFER_BACKEND says otherwise
+    let consumer = consumer_from_env(GROUP_ID, &topics).await?;
 
-    let redis_pool = create_redis_pool().context("redis_pool")?;
-    let sidekiq_client = Client::new(redis_pool, Default::default());
+    let redis_url = env::var("REDIS_URL").unwrap_or("redis://127.0.0.1/".to_string());
+    let redis_conn = redis::Client::open(redis_url)?
+        .get_multiplexed_async_connection()
+        .await
+        .context("redis connection")?;
+    // SCHEMA_REGISTRY_URL, for avro messages
+    let registry = SchemaRegistry::from_env();
+    // CLAIM_CHECK_URL, for bodies kept out of the buffer
+    let claim_check = ClaimCheck::from_env()?.map(Arc::new);
+    if let Some(claim_check) = claim_check.clone() {
+        // bodies older than this are no longer in the buffer, unless jobs were passed their body_ref
+        let retention = Duration::from_secs(
+            env::var("CLAIM_CHECK_RETENTION_S")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(7 * 24 * 3600),
+        );
+        tokio::spawn(async move {
+            loop {
+                if let Err(err) = claim_check.sweep(retention).await {
+                    warn!("claim check sweep failed: {}", err);
+                }
+                tokio::time::sleep(CLAIM_CHECK_SWEEP_INTERVAL).await;
+            }
+        });
+    }
+    let mut sinks: Sinks = HashMap::new();
+    for route in topics_map.values() {
+        if !sinks.contains_key(&route.sink) {
+            sinks.insert(route.sink, make_sink(route.sink, &redis_conn).await?);
+        }
+    }
+    // BATCH_SIZE > 1 writes messages which arrive within BATCH_LINGER_MS together
+    let batch_size: usize = env::var("BATCH_SIZE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1);
+    let linger = Duration::from_millis(
+        env::var("BATCH_LINGER_MS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0),
+    );
     let metrics_listener = TcpListener::bind(metrics_address)
         .await
         .context("metrics_listener")?;
@@ -117,14 +402,18 @@ async fn main() -> anyhow::Result<()> {
         tokio::select! {
             r_message = consumer.recv() => match r_message {
                 Err(err) => {
-                    error!("kafka read error: {}", err);
+                    error!("buffer read error: {}", err);
                     break;
                 }
                 Ok(message) => {
-                    let route = topics_map.get(message.topic()).expect("message came from a topic we subscribed to");
-                    match write_sidekiq_job(&consumer, &sidekiq_client, &route, message).await {
-                        Ok(()) => (),
-                        Err(_) => break,
+                    let mut batch = vec![message];
+                    if let Err(err) = fill_batch(&*consumer, &mut batch, batch_size, linger).await {
+                        error!("buffer read error: {}", err);
+                        break;
+                    }
+                    // failed messages are read again, so carry on
+                    if let Err(err) = write_jobs(&*consumer, &sinks, &registry, claim_check.as_deref(), &topics_map, batch).await {
+                        error!("could not write jobs: {}", err);
                     }
                 }
             },
@@ -150,22 +439,3 @@ async fn main() -> anyhow::Result<()> {
     warn!("Stream processing terminated");
     Ok(())
 }
-
-fn decode_capnp_message(o_bytes: Option<&[u8]>) -> anyhow::Result<Vec<sidekiq::Value>> {
-    let mut job_args = Vec::new();
-    let bytes = o_bytes.ok_or(anyhow!("kafka message has no payload"))?;
-    let reader = capnp::serialize::read_message(bytes, ReaderOptions::new())?;
-    // let typed_reader = TypedReader::<_, buffered_request::Owned>::new(reader);
-    // let buf_req = typed_reader.get()?;
-    let buf_req = reader.get_root::<buffered_request::Reader>()?;
-    let body = buf_req.get_body()?;
-    job_args.push(sidekiq::Value::String(String::from_utf8(body.to_vec())?));
-    let mut headers = Map::new();
-    for h in buf_req.get_headers()? {
-        let name = h.get_name()?.to_str()?.to_owned();
-        let value = String::from_utf8(h.get_value()?.to_vec())?;
-        headers.insert(name, serde_json::Value::String(value));
-    }
-    job_args.push(sidekiq::Value::Object(headers));
-    Ok(job_args)
-}
@@ -2,9 +2,17 @@
 use kafka_buffer::config::*;
 use kafka_buffer::observability;
 use kafka_buffer::observability::hist_time_since;
-use kafka_buffer::encode_request;
+use kafka_buffer::avro::SchemaRegistry;
+use kafka_buffer::claimcheck::ClaimCheck;
+use kafka_buffer::cloudevents;
+use kafka_buffer::content_encoding::{decode_body, DecodeError};
+use kafka_buffer::DecodedRequest;
+use kafka_buffer::encoding::{Encoding, ENCODING_HEADER};
+use kafka_buffer::headers::TemplateVars;
+use kafka_buffer::buffer::{producer_from_env, BufferProducer, SendError};
 
 use anyhow::Context;
+use chrono::Utc;
 use std::env;
 use std::time::Instant;
 use tracing::*;
@@ -12,9 +20,6 @@ use tracing::*;
 extern crate lazy_static;
 use prometheus::{self, register_histogram, register_int_counter, Histogram, IntCounter};
 
-use rdkafka::config::ClientConfig;
-use rdkafka::producer::{FutureProducer, FutureRecord};
-
 use http_body_util::{BodyExt, Empty};
 use hyper::body::Bytes;
 use hyper::server::conn::http1;
@@ -26,7 +31,6 @@ use tokio::net::TcpListener;
 
 #[derive(Clone, Debug)]
 struct Config {
-    kafka_url: String,
     request_max_size: usize,
     topics_map: Routes,
 }
@@ -42,7 +46,7 @@ lazy_static! {
     static ref HTTP_5xx: IntCounter =
         register_int_counter!("http_5xx", "HTTP 5xx responses sent").unwrap();
     static ref KAFKA_DURATION_S: Histogram =
-        register_histogram!("kafka_duration_s", "duration of write requests to Kafka",
+        register_histogram!("kafka_duration_s", "duration of write requests to Kafka, or the buffer backend",
                             vec![0.005, 0.0075, 0.010, 0.032, 0.100, 0.316, 1.0]
 ).unwrap();
 }
@@ -60,9 +64,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .unwrap_or(SocketAddr::from(([0, 0, 0, 0], 9000)));
 
     let config_file_name = env::var("CONFIG_FILE").unwrap_or(DEFAULT_CONFIG_FILE.to_string());
-    let topics_map = parse_from_file(&config_file_name);
+    let mut topics_map = parse_from_file(&config_file_name);
+    // SCHEMA_REGISTRY_URL, if any route is avro
+    let registry = SchemaRegistry::from_env();
+    for route in topics_map.0.values_mut() {
+        if let Encoding::Avro { schema_id } = &mut route.encoding {
+            *schema_id = registry.register(&route.topic).await?;
+        }
+    }
     let config: &'static Config = Box::leak(Box::new(Config {
-        kafka_url: env::var("KAFKA_URL").unwrap_or("localhost:9092".to_string()),
         request_max_size: env::var("REQUEST_MAX_SIZE")
             .ok()
             .and_then(|s| s.parse().ok())
@@ -70,30 +80,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         topics_map,
     }));
 
-    // Create the `FutureProducer` to produce asynchronously.
-    let producer: &'static FutureProducer = Box::leak(Box::new(
-        ClientConfig::new()
-            .set("bootstrap.servers", &config.kafka_url)
-            .set("message.timeout.ms", "1000")
-            .set("connections.max.idle.ms", "30000")
-            .set("linger.ms", "10")
-            .set("compression.codec", "lz4")
-            .create()?,
-    ));
+    // CLAIM_CHECK_URL keeps bodies over CLAIM_CHECK_ABOVE out of the buffer,
+    // so REQUEST_MAX_SIZE can be more than the buffer takes
+    let claim_check: &'static Option<ClaimCheck> = Box::leak(Box::new(ClaimCheck::from_env()?));
+
+    // Kafka, unless BUFFER_BACKEND says otherwise
+    let producer: &'static dyn BufferProducer = Box::leak(producer_from_env().await?);
 
     let write_to_kafka = |req: Request<hyper::body::Incoming>| async {
+        let received_at = Utc::now();
         let path = req.uri().path();
-        match config.topics_map.0.get(path) {
+        match config.topics_map.route_for(path) {
             None => {
                 HTTP_4xx.inc();
                 // I'd like to know which unknown URLs are requested, but not flood our logs
                 empty_http_response(StatusCode::NOT_FOUND)
             }
-            Some(route) => {
-                let (headers, body) = {
+            Some((route, captures)) => {
+                let (uri, mut headers, body) = {
                     let (parts, body) = req.into_parts();
                     let body = http_body_util::Limited::new(body, config.request_max_size);
-                    (parts.headers, body)
+                    (parts.uri, parts.headers, body)
                 };
                 match body.collect().await {
                     Err(err) => {
@@ -102,35 +109,94 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                         empty_http_response(StatusCode::BAD_REQUEST)
                     }
                     Ok(all) => {
-                        let payload = encode_request(&all.to_bytes(), &headers, &route.headers);
-                        let start = Instant::now();
-                        match producer.send_result(FutureRecord::<(), [u8]>::to(&route.topic).payload(&payload))
-                        {
+                        let mut body = all.to_bytes();
+                        if route.decompress {
+                            body = match decode_body(&mut headers, body, config.request_max_size) {
+                                Ok(body) => body,
+                                Err(err) => {
+                                    warn!("refusing request: {}", err);
+                                    HTTP_4xx.inc();
+                                    return empty_http_response(match err {
+                                        DecodeError::Unsupported(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                                        DecodeError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+                                        DecodeError::Invalid(_) => StatusCode::BAD_REQUEST,
+                                    });
+                                }
+                            };
+                        }
+                        if let Some(transform) = &route.transform {
+                            match transform.apply(&body) {
+                                Ok(Some(transformed)) => body = Bytes::from(transformed),
+                                Ok(None) => (),
+                                Err(err) => {
+                                    warn!("refusing request: {}", err);
+                                    HTTP_4xx.inc();
+                                    return empty_http_response(StatusCode::BAD_REQUEST);
+                                }
+                            }
+                        }
+                        let mut kafka_headers = match cloudevents::kafka_headers(route, uri.path(), &mut headers, &mut body) {
+                            Ok(kafka_headers) => kafka_headers,
                             Err(err) => {
-                                warn!("could not enqueue in rdkafka: {:?}", err);
+                                warn!("refusing request: {}", err);
                                 HTTP_4xx.inc();
-                               empty_http_response(StatusCode::TOO_MANY_REQUESTS)
+                                return empty_http_response(StatusCode::BAD_REQUEST);
                             }
-                            Ok(produce_future) => {
-                                let r_delivery = produce_future.await;
-                                hist_time_since(&KAFKA_DURATION_S, start);
-                                match r_delivery {
-                                    Err(_cancelled) => {
-                                        warn!("kafka message canceled");
-                                        HTTP_5xx.inc();
-                                        empty_http_response(StatusCode::INTERNAL_SERVER_ERROR)
-                                    }
-                                    Ok(Err((e, _))) => {
-                                        error!("Kafka Error: {:?}", e);
-                                        HTTP_5xx.inc();
-                                        empty_http_response(StatusCode::SERVICE_UNAVAILABLE)
-                                    }
-                                    Ok(Ok((partition, offset))) => {
-                                        debug!("served request topic={} partition={partition} offset={offset}", route.topic);
-                                        HTTP_200.inc();
-                                        empty_http_response(StatusCode::OK)
-                                    }
+                        };
+                        kafka_headers.push((ENCODING_HEADER.to_string(), route.encoding.header_value()));
+                        let o_body_ref = match claim_check {
+                            None => None,
+                            Some(claim_check) => match claim_check.offload(&route.topic, &body).await {
+                                Ok(o_body_ref) => o_body_ref,
+                                Err(err) => {
+                                    error!("could not offload body: {}", err);
+                                    HTTP_5xx.inc();
+                                    return empty_http_response(StatusCode::SERVICE_UNAVAILABLE);
                                 }
+                            },
+                        };
+                        let rewritten;
+                        let mut values = route.headers.select(&headers);
+                        if !route.header_rewrite.is_empty() {
+                            let vars = TemplateVars {
+                                path: uri.path(),
+                                captures: &captures,
+                                received_at,
+                            };
+                            rewritten = route.header_rewrite.apply(&values, &vars);
+                            values = rewritten.iter().map(|(name, value)| (name, value)).collect();
+                        }
+                        let payload = match o_body_ref {
+                            None => route.encoding.encode_captured(&body, &values, route.compression.as_ref()),
+                            Some(body_ref) => {
+                                let mut request = DecodedRequest::from_http(&[], &values);
+                                request.body_ref = Some(body_ref);
+                                route.encoding.encode(&request, None)
+                            }
+                        };
+                        let start = Instant::now();
+                        let r_delivery = producer.send(&route.topic, &kafka_headers, &payload).await;
+                        hist_time_since(&KAFKA_DURATION_S, start);
+                        match r_delivery {
+                            Err(SendError::Full(err)) => {
+                                warn!("could not enqueue: {}", err);
+                                HTTP_4xx.inc();
+                                empty_http_response(StatusCode::TOO_MANY_REQUESTS)
+                            }
+                            Err(SendError::Cancelled) => {
+                                warn!("buffer write canceled");
+                                HTTP_5xx.inc();
+                                empty_http_response(StatusCode::INTERNAL_SERVER_ERROR)
+                            }
+                            Err(SendError::Failed(err)) => {
+                                error!("buffer error: {}", err);
+                                HTTP_5xx.inc();
+                                empty_http_response(StatusCode::SERVICE_UNAVAILABLE)
+                            }
+                            Ok((partition, offset)) => {
+                                debug!("served request topic={} partition={partition} offset={offset}", route.topic);
+                                HTTP_200.inc();
+                                empty_http_response(StatusCode::OK)
                             }
                         }
                     }
@@ -0,0 +1,75 @@
+use kafka_buffer::archive::SegmentReader;
+use kafka_buffer::avro::SchemaRegistry;
+use kafka_buffer::buffer::producer_from_env;
+use kafka_buffer::encoding::{Encoding, ENCODING_HEADER};
+use kafka_buffer::observability;
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tracing::*;
+
+/// Write the requests in archive segments back to the buffer, in order,
+/// in the encoding and with the headers each was buffered with.
+#[derive(Parser, Debug)]
+struct Cli {
+    /// segments, like archive/foo_topic/20240901T120000.000Z.jsonl.zst
+    #[arg(required = true)]
+    segments: Vec<PathBuf>,
+
+    #[arg(long)]
+    /// topic to write to, instead of the segment's directory name
+    topic: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    observability::init()?;
+    let producer = producer_from_env().await?;
+    let registry = SchemaRegistry::from_env();
+    // topic => avro schema id, registered on first use
+    let mut schema_ids = HashMap::new();
+
+    for path in &cli.segments {
+        if path.to_string_lossy().ends_with(".index.json") {
+            continue;
+        }
+        let topic = match &cli.topic {
+            Some(topic) => topic.clone(),
+            None => path
+                .parent()
+                .and_then(|dir| dir.file_name())
+                .map(|name| name.to_string_lossy().into_owned())
+                .ok_or(anyhow!("can't tell the topic of {}, use --topic", path.display()))?,
+        };
+        let mut count = 0;
+        for r_archived in SegmentReader::open(path)? {
+            let archived = r_archived.with_context(|| format!("reading {}", path.display()))?;
+            let o_value = archived.headers.iter().find(|(name, _)| name == ENCODING_HEADER).map(|(_, value)| value.as_str());
+            let encoding = match Encoding::from_header(o_value)? {
+                Encoding::Avro { .. } => {
+                    let schema_id = match schema_ids.get(&topic) {
+                        Some(schema_id) => *schema_id,
+                        None => {
+                            let schema_id = registry.register(&topic).await?;
+                            schema_ids.insert(topic.clone(), schema_id);
+                            schema_id
+                        }
+                    };
+                    Encoding::Avro { schema_id }
+                }
+                encoding => encoding,
+            };
+            let payload = encoding.encode(&archived.request, None);
+            producer
+                .send(&topic, &archived.headers, &payload)
+                .await
+                .map_err(|err| anyhow!("writing to {}: {}", topic, err))?;
+            count += 1;
+        }
+        info!("replayed {} requests from {} to topic={}", count, path.display(), topic);
+    }
+    Ok(())
+}
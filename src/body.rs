@@ -0,0 +1,132 @@
+//! Job arguments built from the request body.
+
+use crate::config::BodyMode;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::Value;
+
+/// The positional job arguments for a body, before the headers.  Err
+/// explains why the body doesn't fit the route's mode.
+pub fn body_args(mode: &BodyMode, body: &[u8]) -> Result<Vec<Value>, String> {
+    match mode {
+        BodyMode::String => match std::str::from_utf8(body) {
+            Ok(s) => Ok(vec![Value::String(s.to_owned())]),
+            Err(err) => Err(format!("body is not UTF-8: {}", err)),
+        },
+        BodyMode::Json => match serde_json::from_slice(body) {
+            Ok(json) => Ok(vec![json]),
+            Err(err) => Err(format!("body is not JSON: {}", err)),
+        },
+        BodyMode::Base64 => Ok(vec![Value::String(STANDARD.encode(body))]),
+        BodyMode::JsonPath(paths) => match serde_json::from_slice::<Value>(body) {
+            Ok(json) => Ok(paths
+                .iter()
+                .map(|path| path.select(&json).cloned().unwrap_or(Value::Null))
+                .collect()),
+            Err(err) => Err(format!("body is not JSON: {}", err)),
+        },
+    }
+}
+
+/// the body as a string if it is UTF-8, otherwise base64
+pub fn lossless_string(body: &[u8]) -> String {
+    match std::str::from_utf8(body) {
+        Ok(s) => s.to_owned(),
+        Err(_) => STANDARD.encode(body),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Step {
+    Key(String),
+    Index(usize),
+}
+
+/// The subset of JSONPath which selects a single value: `$`, followed by
+/// `.key`, `['key']`, or `[index]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JsonPath(Vec<Step>);
+
+impl JsonPath {
+    pub fn parse(s: &str) -> Result<JsonPath, String> {
+        let mut rest = s
+            .strip_prefix('$')
+            .ok_or(format!("jsonpath must start with $, got {}", s))?;
+        let mut steps = Vec::new();
+        while !rest.is_empty() {
+            if let Some(r) = rest.strip_prefix('.') {
+                let end = r.find(|c: char| c == '.' || c == '[').unwrap_or(r.len());
+                if end == 0 {
+                    return Err(format!("empty key in jsonpath {}", s));
+                }
+                steps.push(Step::Key(r[..end].to_owned()));
+                rest = &r[end..];
+            } else if let Some(r) = rest.strip_prefix('[') {
+                let end = r.find(']').ok_or(format!("unclosed [ in jsonpath {}", s))?;
+                let inner = &r[..end];
+                match inner.strip_prefix('\'').and_then(|k| k.strip_suffix('\'')) {
+                    Some(key) => steps.push(Step::Key(key.to_owned())),
+                    None => match inner.parse() {
+                        Ok(i) => steps.push(Step::Index(i)),
+                        Err(_) => return Err(format!("expected 'key' or index in jsonpath {}, got [{}]", s, inner)),
+                    },
+                }
+                rest = &r[end + 1..];
+            } else {
+                return Err(format!("expected . or [ in jsonpath {}, got {}", s, rest));
+            }
+        }
+        Ok(JsonPath(steps))
+    }
+
+    pub fn select<'a>(&self, json: &'a Value) -> Option<&'a Value> {
+        self.0.iter().try_fold(json, |v, step| match step {
+            Step::Key(k) => v.get(k.as_str()),
+            Step::Index(i) => v.get(*i),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn select(path: &str, json: &Value) -> Option<Value> {
+        JsonPath::parse(path).unwrap().select(json).cloned()
+    }
+
+    #[test]
+    fn jsonpath_select() {
+        let json = json!({"id": 7, "data": {"items": [{"sku": "a"}, {"sku": "b"}]}, "event-type": "created", "a.b": 1});
+        assert_eq!(select("$", &json), Some(json.clone()));
+        assert_eq!(select("$.id", &json), Some(json!(7)));
+        assert_eq!(select("$.data.items[1].sku", &json), Some(json!("b")));
+        assert_eq!(select("$['event-type']", &json), Some(json!("created")));
+        assert_eq!(select("$['a.b']", &json), Some(json!(1)));
+        assert_eq!(select("$.data['items'][0]", &json), Some(json!({"sku": "a"})));
+        assert_eq!(select("$.data.items[2]", &json), None);
+        assert_eq!(select("$.missing.id", &json), None);
+        // indexes only select from arrays
+        assert_eq!(select("$.data[0]", &json), None);
+    }
+
+    #[test]
+    fn jsonpath_parse() {
+        assert_eq!(JsonPath::parse("id"), Err("jsonpath must start with $, got id".to_string()));
+        assert_eq!(JsonPath::parse("$..id"), Err("empty key in jsonpath $..id".to_string()));
+        assert_eq!(JsonPath::parse("$[0"), Err("unclosed [ in jsonpath $[0".to_string()));
+        assert_eq!(JsonPath::parse("$[*]"), Err("expected 'key' or index in jsonpath $[*], got [*]".to_string()));
+        assert_eq!(JsonPath::parse("$id"), Err("expected . or [ in jsonpath $id, got id".to_string()));
+    }
+
+    #[test]
+    fn jsonpath_args() {
+        let paths = ["$.id", "$.missing"].map(|p| JsonPath::parse(p).unwrap()).to_vec();
+        let args = body_args(&BodyMode::JsonPath(paths), br#"{"id": 7}"#);
+        assert_eq!(args, Ok(vec![json!(7), Value::Null]));
+        assert!(body_args(&BodyMode::Json, b"{").unwrap_err().starts_with("body is not JSON"));
+        assert!(body_args(&BodyMode::String, &[0xff]).unwrap_err().starts_with("body is not UTF-8"));
+        assert_eq!(body_args(&BodyMode::Base64, &[0xff]), Ok(vec![json!("/w==")]));
+    }
+}
@@ -0,0 +1,147 @@
+//! Where requests wait between the producer and the consumer.  Kafka by
+//! default, BUFFER_BACKEND=disk for a log in a local directory, or
+//! BUFFER_BACKEND=redis for Redis Streams.
+
+use crate::disklog::{DiskLogConsumer, DiskLogProducer};
+use crate::kafka::{KafkaConsumer, KafkaProducer};
+use crate::redis_buffer::{RedisBufferConsumer, RedisBufferProducer};
+use anyhow::Context;
+use async_trait::async_trait;
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// one buffered request, as the consumer reads it
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    /// (name, value), like Kafka headers
+    pub headers: Vec<(String, String)>,
+    pub payload: Vec<u8>,
+    /// when the producer wrote it, in milliseconds since the epoch, if the
+    /// backend keeps that
+    pub timestamp_ms: Option<i64>,
+}
+
+impl Message {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+/// why the producer couldn't buffer a request
+#[derive(Debug)]
+pub enum SendError {
+    /// too many requests waiting to be written, try again later
+    Full(String),
+    /// gave up before the write finished
+    Cancelled,
+    Failed(String),
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::Full(msg) => write!(f, "buffer full: {}", msg),
+            SendError::Cancelled => write!(f, "write cancelled"),
+            SendError::Failed(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+#[async_trait]
+pub trait BufferProducer: Send + Sync {
+    /// Ok once the payload is durable, with its partition and offset.
+    /// `headers` are stored alongside, like Kafka headers.
+    async fn send(&self, topic: &str, headers: &[(String, String)], payload: &[u8]) -> Result<(i32, i64), SendError>;
+}
+
+#[async_trait]
+pub trait BufferConsumer: Send + Sync {
+    /// The next message from any subscribed topic.  Cancelling this
+    /// future loses no messages.
+    async fn recv(&self) -> anyhow::Result<Message>;
+
+    /// true the first time it is called for a partition after it is
+    /// assigned to this consumer
+    fn take_assigned(&self, topic: &str, partition: i32) -> bool;
+
+    /// read a partition from `offset` next
+    async fn seek(&self, topic: &str, partition: i32, offset: i64) -> anyhow::Result<()>;
+
+    /// Record (topic, partition, next offset to read), so a restarted
+    /// consumer starts there.
+    async fn commit(&self, offsets: &[(String, i32, i64)]) -> anyhow::Result<()>;
+}
+
+/// DISK_LOG_DIR, shared by the producer and consumer on one host
+fn disk_log_dir() -> PathBuf {
+    PathBuf::from(env::var("DISK_LOG_DIR").unwrap_or("buffer".to_string()))
+}
+
+/// REDIS_BUFFER_URL, or else the consumer's REDIS_URL
+async fn redis_buffer_connection() -> anyhow::Result<redis::aio::MultiplexedConnection> {
+    let url = env::var("REDIS_BUFFER_URL")
+        .or(env::var("REDIS_URL"))
+        .unwrap_or("redis://127.0.0.1/".to_string());
+    redis::Client::open(url)?
+        .get_multiplexed_async_connection()
+        .await
+        .context("redis buffer connection")
+}
+
+pub async fn producer_from_env() -> anyhow::Result<Box<dyn BufferProducer>> {
+    match env::var("BUFFER_BACKEND").as_deref() {
+        Ok("kafka") | Err(_) => {
+            let kafka_url = env::var("KAFKA_URL").unwrap_or("localhost:9092".to_string());
+            Ok(Box::new(KafkaProducer::new(&kafka_url)?))
+        }
+        Ok("disk") => {
+            let var = |name: &str, default: u64| env::var(name).ok().and_then(|s| s.parse().ok()).unwrap_or(default);
+            Ok(Box::new(DiskLogProducer::new(
+                &disk_log_dir(),
+                var("DISK_LOG_SEGMENT_BYTES", 64 << 20),
+                // same default as Kafka's retention.ms
+                Duration::from_secs(var("DISK_LOG_RETENTION_S", 7 * 24 * 3600)),
+            )?))
+        }
+        Ok("redis") => {
+            // REDIS_BUFFER_MAXLEN trims each stream, like Kafka's retention
+            let maxlen = env::var("REDIS_BUFFER_MAXLEN").ok().and_then(|s| s.parse().ok());
+            Ok(Box::new(RedisBufferProducer::new(redis_buffer_connection().await?, maxlen)))
+        }
+        Ok(other) => Err(anyhow::anyhow!("BUFFER_BACKEND must be kafka, disk, or redis, got {}", other)),
+    }
+}
+
+pub async fn consumer_from_env(group_id: &str, topics: &[&str]) -> anyhow::Result<Box<dyn BufferConsumer>> {
+    match env::var("BUFFER_BACKEND").as_deref() {
+        Ok("kafka") | Err(_) => {
+            let kafka_url = env::var("KAFKA_URL").unwrap_or("localhost:9092".to_string());
+            Ok(Box::new(KafkaConsumer::new(&kafka_url, group_id, topics)?))
+        }
+        Ok("disk") => Ok(Box::new(DiskLogConsumer::new(&disk_log_dir(), group_id, topics)?)),
+        Ok("redis") => {
+            let consumer_name = env::var("REDIS_BUFFER_CONSUMER")
+                .or(env::var("HOSTNAME"))
+                .unwrap_or("kafka-buffer".to_string());
+            let claim_idle_ms = env::var("REDIS_BUFFER_CLAIM_IDLE_MS")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(60_000);
+            Ok(Box::new(
+                RedisBufferConsumer::new(
+                    redis_buffer_connection().await?,
+                    group_id,
+                    &consumer_name,
+                    Duration::from_millis(claim_idle_ms),
+                    topics,
+                )
+                .await?,
+            ))
+        }
+        Ok(other) => Err(anyhow::anyhow!("BUFFER_BACKEND must be kafka, disk, or redis, got {}", other)),
+    }
+}
@@ -0,0 +1,255 @@
+//! BullMQ jobs, for Node.js workers, in BullMQ 5's Redis layout.
+//!
+//! One Lua script writes the job hash, the wait list or delayed set, and
+//! the event stream entries, as BullMQ's own addJob script does.
+
+use crate::body::lossless_string;
+use crate::config::Route;
+use crate::jid;
+use crate::job::run_at;
+use crate::observability::hist_time_since;
+use crate::sink::{JobSink, Outcome, Record};
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use prometheus::{register_histogram, Histogram};
+use redis::aio::MultiplexedConnection;
+use redis::Script;
+use serde_json::{json, Map, Value};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::*;
+
+lazy_static! {
+    static ref BULLMQ_DURATION_S: Histogram =
+        register_histogram!("bullmq_duration_s", "duration of writes to BullMQ queues").unwrap();
+}
+
+// KEYS: wait, paused, meta, id, delayed, events, marker, failed
+// ARGV: job key prefix, job id, name, data, opts, timestamp ms, delay ms,
+//       failed reason (empty unless the request can't become a job)
+// returns 0 if the job already exists, 1 waiting, 2 delayed, 3 failed
+// a delayed job's score is its due time in ms * 0x1000, plus the low 12 bits
+// of the job counter, so jobs due in the same ms keep their order
+const ADD_JOB: &str = r#"
+local jobKey = ARGV[1] .. ARGV[2]
+if redis.call('EXISTS', jobKey) == 1 then
+    return 0
+end
+local jobCounter = redis.call('INCR', KEYS[4])
+local maxEvents = tonumber(redis.call('HGET', KEYS[3], 'opts.maxLenEvents') or 10000)
+local timestamp = tonumber(ARGV[6])
+local delay = tonumber(ARGV[7])
+redis.call('HMSET', jobKey, 'name', ARGV[3], 'data', ARGV[4], 'opts', ARGV[5],
+    'timestamp', timestamp, 'delay', delay, 'priority', 0)
+redis.call('XADD', KEYS[6], 'MAXLEN', '~', maxEvents, '*', 'event', 'added', 'jobId', ARGV[2], 'name', ARGV[3])
+if ARGV[8] ~= '' then
+    redis.call('HMSET', jobKey, 'failedReason', ARGV[8], 'finishedOn', timestamp, 'attemptsMade', 0)
+    redis.call('ZADD', KEYS[8], timestamp, ARGV[2])
+    redis.call('XADD', KEYS[6], 'MAXLEN', '~', maxEvents, '*', 'event', 'failed', 'jobId', ARGV[2],
+        'failedReason', ARGV[8], 'prev', 'waiting')
+    return 3
+elseif delay > 0 then
+    local delayedTimestamp = timestamp + delay
+    redis.call('ZADD', KEYS[5], delayedTimestamp * 0x1000 + bit.band(jobCounter, 0xfff), ARGV[2])
+    redis.call('XADD', KEYS[6], 'MAXLEN', '~', maxEvents, '*', 'event', 'delayed', 'jobId', ARGV[2],
+        'delay', delayedTimestamp)
+    -- wake a worker when the earliest delayed job is due
+    local first = redis.call('ZRANGE', KEYS[5], 0, 0, 'WITHSCORES')
+    if first[2] then
+        redis.call('ZADD', KEYS[7], math.floor(tonumber(first[2]) / 0x1000), '1')
+    end
+    return 2
+else
+    local paused = redis.call('HEXISTS', KEYS[3], 'paused') == 1
+    if paused then
+        redis.call('LPUSH', KEYS[2], ARGV[2])
+    else
+        redis.call('LPUSH', KEYS[1], ARGV[2])
+        redis.call('ZADD', KEYS[7], 0, '0')
+    end
+    redis.call('XADD', KEYS[6], 'MAXLEN', '~', maxEvents, '*', 'event', 'waiting', 'jobId', ARGV[2])
+    return 1
+end
+"#;
+
+pub struct BullMq {
+    conn: MultiplexedConnection,
+    /// BullMQ's `prefix` option, "bull" by default
+    prefix: String,
+    script: Script,
+}
+
+impl BullMq {
+    pub fn new(conn: MultiplexedConnection, prefix: &str) -> BullMq {
+        BullMq {
+            conn,
+            prefix: prefix.to_owned(),
+            script: Script::new(ADD_JOB),
+        }
+    }
+}
+
+/// the `opts` BullMQ stores with each job
+fn job_opts(route: &Route, job_id: &str, delay_ms: u64) -> Value {
+    let mut opts = Map::new();
+    opts.insert("jobId".to_owned(), json!(job_id));
+    if let Some(attempts) = route.attempts {
+        opts.insert("attempts".to_owned(), json!(attempts));
+    }
+    if let Some(backoff) = &route.backoff {
+        opts.insert("backoff".to_owned(), backoff.clone());
+    }
+    if delay_ms > 0 {
+        opts.insert("delay".to_owned(), json!(delay_ms));
+    }
+    Value::Object(opts)
+}
+
+/// the KEYS and ARGV of ADD_JOB for a record, at `now` since the epoch
+fn add_job_call(prefix: &str, route: &Route, record: &Record, now: Duration) -> (Vec<String>, Vec<String>) {
+    let job_id = jid::for_record(route, record);
+    let (args, delay_ms, failed_reason) = match record.body_args(route) {
+        Ok(args) => {
+            let delay_ms = run_at(route, record)
+                .map(|at| ((at - now.as_secs_f64()) * 1000.0).max(0.0) as u64)
+                .unwrap_or(0);
+            (args, delay_ms, String::new())
+        }
+        Err(reason) => {
+            warn!("writing job id={} as failed: {}", job_id, reason);
+            (vec![Value::String(lossless_string(&record.request.body))], 0, reason)
+        }
+    };
+    let data = json!({
+        "args": args,
+        "headers": record.request.headers,
+    });
+    let key = |name: &str| format!("{}:{}:{}", prefix, route.queue, name);
+    let keys = ["wait", "paused", "meta", "id", "delayed", "events", "marker", "failed"].map(key).to_vec();
+    let argv = vec![
+        key(""),
+        job_id.clone(),
+        route.job_class.clone(),
+        data.to_string(),
+        job_opts(route, &job_id, delay_ms).to_string(),
+        now.as_millis().to_string(),
+        delay_ms.to_string(),
+        failed_reason,
+    ];
+    (keys, argv)
+}
+
+#[async_trait]
+impl JobSink for BullMq {
+    async fn push(&self, route: &Route, record: &Record) -> anyhow::Result<Outcome> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let (keys, argv) = add_job_call(&self.prefix, route, record, now);
+        let mut conn = self.conn.clone();
+        let mut invocation = self.script.prepare_invoke();
+        for key in &keys {
+            invocation.key(key);
+        }
+        for arg in &argv {
+            invocation.arg(arg);
+        }
+        let start = Instant::now();
+        let r_added: redis::RedisResult<i64> = invocation.invoke_async(&mut conn).await;
+        hist_time_since(&BULLMQ_DURATION_S, start);
+        Ok(match r_added? {
+            0 => Outcome::Duplicate,
+            2 => Outcome::Scheduled,
+            3 => Outcome::DeadLettered,
+            _ => Outcome::Enqueued,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse;
+    use crate::DecodedRequest;
+
+    fn route(options: &str) -> Route {
+        let config = format!(
+            r#"(("/t" . ((sink . "bullmq") (job-name . "handle") (queue . "webhooks") (topic . "t") (body . "json") {})))"#,
+            options
+        );
+        parse(&config).unwrap().0.remove("/t").unwrap()
+    }
+
+    fn record(body: &str, received_at: f64) -> Record {
+        let mut headers = Map::new();
+        headers.insert("content-type".to_owned(), json!("application/json"));
+        Record {
+            topic: "t".to_owned(),
+            partition: 0,
+            offset: 7,
+            request: DecodedRequest {
+                body: body.as_bytes().to_vec(),
+                headers,
+                body_ref: None,
+            },
+            headers: Vec::new(),
+            received_at,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn opts() {
+        assert_eq!(job_opts(&route(""), "t-0-7", 0), json!({"jobId": "t-0-7"}));
+        let route = route("(attempts . 5) (backoff . 1000)");
+        assert_eq!(
+            job_opts(&route, "t-0-7", 10_000),
+            json!({"jobId": "t-0-7", "attempts": 5, "backoff": {"type": "fixed", "delay": 1000}, "delay": 10_000})
+        );
+    }
+
+    #[test]
+    fn keys_and_argv() {
+        let now = Duration::from_millis(1_700_000_000_000);
+        let (keys, argv) = add_job_call("bull", &route(""), &record(r#"{"id": 1}"#, now.as_secs_f64()), now);
+        assert_eq!(
+            keys,
+            [
+                "bull:webhooks:wait",
+                "bull:webhooks:paused",
+                "bull:webhooks:meta",
+                "bull:webhooks:id",
+                "bull:webhooks:delayed",
+                "bull:webhooks:events",
+                "bull:webhooks:marker",
+                "bull:webhooks:failed",
+            ]
+        );
+        assert_eq!(argv[..3], ["bull:webhooks:", "t-0-7", "handle"]);
+        let data: Value = serde_json::from_str(&argv[3]).unwrap();
+        assert_eq!(data, json!({"args": [{"id": 1}], "headers": {"content-type": "application/json"}}));
+        assert_eq!(argv[4], json!({"jobId": "t-0-7"}).to_string());
+        assert_eq!(argv[5..], ["1700000000000", "0", ""]);
+    }
+
+    #[test]
+    fn delayed() {
+        let now = Duration::from_millis(1_700_000_000_000);
+        // the route delay is in seconds, BullMQ's in milliseconds
+        let received_at = now.as_secs_f64() - 4.0;
+        let (_, argv) = add_job_call("bull", &route("(delay . 10)"), &record("{}", received_at), now);
+        assert_eq!(argv[4], json!({"jobId": "t-0-7", "delay": 6000}).to_string());
+        assert_eq!(argv[6], "6000");
+
+        // due already
+        let (_, argv) = add_job_call("bull", &route("(delay . 10)"), &record("{}", received_at - 60.0), now);
+        assert_eq!(argv[6], "0");
+    }
+
+    #[test]
+    fn failed() {
+        let now = Duration::from_millis(1_700_000_000_000);
+        let (_, argv) = add_job_call("bull", &route("(delay . 10)"), &record("not json", now.as_secs_f64()), now);
+        let data: Value = serde_json::from_str(&argv[3]).unwrap();
+        assert_eq!(data["args"], json!(["not json"]));
+        assert_eq!(argv[6], "0");
+        assert!(argv[7].starts_with("body is not JSON"), "{}", argv[7]);
+    }
+}
@@ -0,0 +1,194 @@
+//! Celery tasks on a Redis broker, in Celery's message protocol v2.
+//!
+//! https://docs.celeryq.dev/en/stable/internals/protocol.html
+
+use crate::body::lossless_string;
+use crate::config::Route;
+use crate::jid;
+use crate::job::run_at;
+use crate::observability::hist_time_since;
+use crate::sink::{JobSink, Outcome, Record};
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, SecondsFormat, Utc};
+use lazy_static::lazy_static;
+use prometheus::{register_histogram, Histogram};
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use serde_json::{json, Value};
+use std::time::Instant;
+use tracing::*;
+
+lazy_static! {
+    static ref CELERY_DURATION_S: Histogram =
+        register_histogram!("celery_duration_s", "duration of writes to Celery queues").unwrap();
+}
+
+pub struct Celery {
+    conn: MultiplexedConnection,
+}
+
+impl Celery {
+    pub fn new(conn: MultiplexedConnection) -> Celery {
+        Celery { conn }
+    }
+}
+
+/// The message kombu's Redis transport pushes for `apply_async`.  `eta` is
+/// in seconds since the epoch.
+pub fn celery_message(route: &Route, task_id: &str, args: Vec<Value>, eta: Option<f64>) -> Value {
+    let argsrepr = Value::Array(args.clone()).to_string();
+    let body = json!([
+        args,
+        {},
+        {"callbacks": null, "errbacks": null, "chain": null, "chord": null},
+    ]);
+    json!({
+        "body": STANDARD.encode(body.to_string()),
+        "content-encoding": "utf-8",
+        "content-type": "application/json",
+        "headers": {
+            "lang": "py",
+            "task": route.job_class,
+            "id": task_id,
+            "shadow": null,
+            "eta": eta.and_then(iso8601),
+            "expires": null,
+            "group": null,
+            "group_index": null,
+            "retries": 0,
+            "timelimit": [null, null],
+            "root_id": task_id,
+            "parent_id": null,
+            "argsrepr": argsrepr,
+            "kwargsrepr": "{}",
+            "origin": "kafka-buffer",
+            "ignore_result": false,
+        },
+        "properties": {
+            "correlation_id": task_id,
+            "reply_to": "",
+            "delivery_mode": 2,
+            "delivery_info": {"exchange": "", "routing_key": route.queue},
+            "priority": 0,
+            "body_encoding": "base64",
+            "delivery_tag": task_id,
+        },
+    })
+}
+
+fn iso8601(epoch_s: f64) -> Option<String> {
+    let at = DateTime::<Utc>::from_timestamp(epoch_s.trunc() as i64, (epoch_s.fract() * 1e9) as u32)?;
+    Some(at.to_rfc3339_opts(SecondsFormat::Micros, true))
+}
+
+/// Celery has no dead set, so requests which can't become tasks go to a
+/// list next to the queue.
+fn dead_key(queue: &str) -> String {
+    format!("{}:dead", queue)
+}
+
+#[async_trait]
+impl JobSink for Celery {
+    async fn push(&self, route: &Route, record: &Record) -> anyhow::Result<Outcome> {
+        let task_id = jid::for_record(route, record);
+        let (key, outcome, message) = match record.body_args(route) {
+            Ok(mut args) => {
+                args.push(Value::Object(record.request.headers.clone()));
+                // workers hold tasks with an eta until it passes
+                let eta = run_at(route, record);
+                let outcome = match eta {
+                    None => Outcome::Enqueued,
+                    Some(_) => Outcome::Scheduled,
+                };
+                (route.queue.clone(), outcome, celery_message(route, &task_id, args, eta))
+            }
+            Err(reason) => {
+                warn!("writing task id={} to {}: {}", task_id, dead_key(&route.queue), reason);
+                let args = vec![
+                    Value::String(lossless_string(&record.request.body)),
+                    Value::Object(record.request.headers.clone()),
+                ];
+                let mut message = celery_message(route, &task_id, args, None);
+                message["headers"]["kafka_buffer_error"] = json!(reason);
+                (dead_key(&route.queue), Outcome::DeadLettered, message)
+            }
+        };
+        let mut conn = self.conn.clone();
+        let start = Instant::now();
+        let r_push: redis::RedisResult<()> = conn.lpush(key, message.to_string()).await;
+        hist_time_since(&CELERY_DURATION_S, start);
+        r_push?;
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse;
+
+    fn route() -> Route {
+        let config = r#"(("/t" . ((sink . "celery") (task . "webhooks.tasks.handle") (queue . "webhooks") (topic . "t"))))"#;
+        parse(config).unwrap().0.remove("/t").unwrap()
+    }
+
+    fn args() -> Vec<Value> {
+        vec![json!("{\"id\":1}"), json!({"content-type": "application/json"})]
+    }
+
+    /// what kombu's Redis transport pushes for
+    /// `handle.apply_async(args=args, task_id="t-0-7", queue="webhooks")`
+    #[test]
+    fn protocol_v2_message() {
+        let expected = json!({
+            "body": "W1sie1wiaWRcIjoxfSIseyJjb250ZW50LXR5cGUiOiJhcHBsaWNhdGlvbi9qc29uIn1dLHt9LHsiY2FsbGJhY2tzIjpudWxsLCJjaGFpbiI6bnVsbCwiY2hvcmQiOm51bGwsImVycmJhY2tzIjpudWxsfV0=",
+            "content-encoding": "utf-8",
+            "content-type": "application/json",
+            "headers": {
+                "lang": "py",
+                "task": "webhooks.tasks.handle",
+                "id": "t-0-7",
+                "shadow": null,
+                "eta": null,
+                "expires": null,
+                "group": null,
+                "group_index": null,
+                "retries": 0,
+                "timelimit": [null, null],
+                "root_id": "t-0-7",
+                "parent_id": null,
+                "argsrepr": "[\"{\\\"id\\\":1}\",{\"content-type\":\"application/json\"}]",
+                "kwargsrepr": "{}",
+                "origin": "kafka-buffer",
+                "ignore_result": false,
+            },
+            "properties": {
+                "correlation_id": "t-0-7",
+                "reply_to": "",
+                "delivery_mode": 2,
+                "delivery_info": {"exchange": "", "routing_key": "webhooks"},
+                "priority": 0,
+                "body_encoding": "base64",
+                "delivery_tag": "t-0-7",
+            },
+        });
+        assert_eq!(celery_message(&route(), "t-0-7", args(), None), expected);
+    }
+
+    /// the body is (args, kwargs, embed), as Celery's worker unpacks it
+    #[test]
+    fn body_decodes() {
+        let message = celery_message(&route(), "t-0-7", args(), None);
+        let body = STANDARD.decode(message["body"].as_str().unwrap()).unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!([args(), {}, {"callbacks": null, "errbacks": null, "chain": null, "chord": null}]));
+    }
+
+    #[test]
+    fn eta_is_iso8601() {
+        let message = celery_message(&route(), "t-0-7", args(), Some(1_700_000_000.25));
+        assert_eq!(message["headers"]["eta"], "2023-11-14T22:13:20.250000Z");
+    }
+}
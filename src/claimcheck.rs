@@ -0,0 +1,195 @@
+//! Claim checks for bodies too large for the buffer.  The producer writes
+//! them to an object store, a directory (`file:///var/lib/bodies`) or an
+//! S3-compatible bucket (`s3://bucket/prefix`, with AWS_ENDPOINT and
+//! AWS_ALLOW_HTTP for MinIO), and buffers the request with an empty body
+//! and the object's URL as its body_ref.  The consumer fetches the body
+//! back, or passes the URL to the job, and sweeps objects older than any
+//! message which could still refer to them.
+
+use anyhow::anyhow;
+use chrono::Utc;
+use futures::TryStreamExt;
+use lazy_static::lazy_static;
+use object_store::path::Path;
+use object_store::{parse_url_opts, ObjectStore, PutPayload};
+use prometheus::{register_int_counter, IntCounter};
+use rand::Rng;
+use std::env;
+use std::time::Duration;
+use tracing::*;
+use url::Url;
+
+lazy_static! {
+    static ref CLAIM_CHECK_OFFLOADED: IntCounter =
+        register_int_counter!("claim_check_offloaded", "number of bodies written to the object store").unwrap();
+    static ref CLAIM_CHECK_FETCHED: IntCounter =
+        register_int_counter!("claim_check_fetched", "number of bodies read back from the object store").unwrap();
+    static ref CLAIM_CHECK_SWEPT: IntCounter =
+        register_int_counter!("claim_check_swept", "number of expired bodies deleted from the object store").unwrap();
+}
+
+/// the header which passes a body_ref to jobs
+pub const BODY_REF_HEADER: &str = "kafka-buffer-body-ref";
+
+#[derive(Debug)]
+pub enum FetchError {
+    /// swept, or never in this store, so trying again won't help
+    Gone(String),
+    /// the store didn't answer, try again later
+    Unavailable(anyhow::Error),
+}
+
+impl FetchError {
+    pub fn is_transient(&self) -> bool {
+        matches!(self, FetchError::Unavailable(_))
+    }
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchError::Gone(reason) => write!(f, "{}", reason),
+            FetchError::Unavailable(err) => write!(f, "object store unavailable: {}", err),
+        }
+    }
+}
+
+pub struct ClaimCheck {
+    store: Box<dyn ObjectStore>,
+    /// CLAIM_CHECK_URL, which each body_ref starts with
+    url: String,
+    /// the path in the store under `url`
+    prefix: Path,
+    /// offload bodies larger than this many bytes
+    above: usize,
+}
+
+impl ClaimCheck {
+    pub fn new(url: &str, above: usize) -> anyhow::Result<ClaimCheck> {
+        // AWS_* settings, like AWS_ENDPOINT, for S3-compatible stores
+        let options = env::vars()
+            .filter(|(k, _)| k.starts_with("AWS_"))
+            .map(|(k, v)| (k.to_ascii_lowercase(), v));
+        let (store, prefix) = parse_url_opts(&Url::parse(url)?, options)?;
+        Ok(ClaimCheck {
+            store,
+            url: url.trim_end_matches('/').to_owned(),
+            prefix,
+            above,
+        })
+    }
+
+    /// CLAIM_CHECK_URL, with bodies over CLAIM_CHECK_ABOVE bytes offloaded,
+    /// or None if it isn't set
+    pub fn from_env() -> anyhow::Result<Option<ClaimCheck>> {
+        let Ok(url) = env::var("CLAIM_CHECK_URL") else {
+            return Ok(None);
+        };
+        let above = env::var("CLAIM_CHECK_ABOVE")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(256 << 10);
+        Ok(Some(ClaimCheck::new(&url, above)?))
+    }
+
+    /// the object for a key like `topic/date/id`
+    fn path(&self, key: &str) -> Path {
+        key.split('/').fold(self.prefix.clone(), |path, part| path.child(part))
+    }
+
+    /// Write a body to the store if it is too large to buffer, returning
+    /// its body_ref.
+    pub async fn offload(&self, topic: &str, body: &[u8]) -> anyhow::Result<Option<String>> {
+        if body.len() <= self.above {
+            return Ok(None);
+        }
+        let id: u128 = rand::thread_rng().gen();
+        let key = format!("{}/{}/{:032x}", topic, Utc::now().format("%Y%m%d"), id);
+        self.store.put(&self.path(&key), PutPayload::from(body.to_vec())).await?;
+        CLAIM_CHECK_OFFLOADED.inc();
+        Ok(Some(format!("{}/{}", self.url, key)))
+    }
+
+    pub async fn fetch(&self, body_ref: &str) -> Result<Vec<u8>, FetchError> {
+        let key = body_ref
+            .strip_prefix(&self.url)
+            .and_then(|k| k.strip_prefix('/'))
+            .ok_or_else(|| FetchError::Gone(format!("body_ref {} is not in CLAIM_CHECK_URL {}", body_ref, self.url)))?;
+        let r_body = match self.store.get(&self.path(key)).await {
+            Ok(result) => result.bytes().await,
+            Err(err) => Err(err),
+        };
+        match r_body {
+            Ok(body) => {
+                CLAIM_CHECK_FETCHED.inc();
+                Ok(body.to_vec())
+            }
+            Err(object_store::Error::NotFound { .. }) => Err(FetchError::Gone(format!("{} not found", body_ref))),
+            Err(err) => Err(FetchError::Unavailable(err.into())),
+        }
+    }
+
+    /// Delete bodies written longer ago than `retention`.
+    pub async fn sweep(&self, retention: Duration) -> anyhow::Result<usize> {
+        let cutoff = Utc::now() - chrono::Duration::from_std(retention)?;
+        let expired: Vec<Path> = self
+            .store
+            .list(Some(&self.prefix))
+            .try_filter(|meta| futures::future::ready(meta.last_modified < cutoff))
+            .map_ok(|meta| meta.location)
+            .try_collect()
+            .await?;
+        for path in &expired {
+            self.store.delete(path).await?;
+            CLAIM_CHECK_SWEPT.inc();
+        }
+        info!("swept {} expired bodies from {}", expired.len(), self.url);
+        Ok(expired.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claim_check(name: &str) -> (std::path::PathBuf, ClaimCheck) {
+        let dir = env::temp_dir().join(format!("claimcheck-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let claim_check = ClaimCheck::new(&format!("file://{}/", dir.display()), 4).unwrap();
+        (dir, claim_check)
+    }
+
+    #[tokio::test]
+    async fn offload_and_fetch() {
+        let (dir, claim_check) = claim_check("fetch");
+        assert_eq!(claim_check.offload("t", b"four").await.unwrap(), None);
+        let body_ref = claim_check.offload("t", b"five!").await.unwrap().unwrap();
+        let prefix = format!("file://{}/t/{}/", dir.display(), Utc::now().format("%Y%m%d"));
+        assert!(body_ref.starts_with(&prefix), "{}", body_ref);
+        assert_eq!(claim_check.fetch(&body_ref).await.unwrap(), b"five!");
+        // each body gets its own object
+        assert_ne!(claim_check.offload("t", b"five!").await.unwrap().unwrap(), body_ref);
+
+        let missing = format!("{}{:032x}", prefix, 0);
+        assert!(matches!(claim_check.fetch(&missing).await, Err(FetchError::Gone(_))));
+        let elsewhere = "s3://bucket/t/20240901/00";
+        assert!(matches!(claim_check.fetch(elsewhere).await, Err(FetchError::Gone(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sweep_past_retention() {
+        let (dir, claim_check) = claim_check("sweep");
+        let old = claim_check.offload("t", b"old body").await.unwrap().unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let new = claim_check.offload("t", b"new body").await.unwrap().unwrap();
+
+        assert_eq!(claim_check.sweep(Duration::from_millis(150)).await.unwrap(), 1);
+        // what the consumer dead letters
+        assert!(matches!(claim_check.fetch(&old).await, Err(FetchError::Gone(_))));
+        assert_eq!(claim_check.fetch(&new).await.unwrap(), b"new body");
+        assert_eq!(claim_check.sweep(Duration::from_secs(3600)).await.unwrap(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
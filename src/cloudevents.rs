@@ -0,0 +1,328 @@
+//! CloudEvents.  The producer accepts events in either HTTP content mode,
+//! binary (attributes in `ce-*` headers) or structured (the whole event as
+//! `application/cloudevents+json`), and buffers them in the binary mode of
+//! the Kafka protocol binding: attributes in `ce_<name>` headers, and
+//! `content-type` for datacontenttype.  A structured event is converted, so
+//! the body buffered is its data, decoded from data_base64 if need be, and
+//! the request's content-type becomes its datacontenttype.
+
+use crate::config::Route;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{SecondsFormat, Utc};
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use rand::Rng;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+lazy_static! {
+    static ref CLOUDEVENTS_RECEIVED: IntCounter =
+        register_int_counter!("cloudevents_received", "number of requests which were CloudEvents").unwrap();
+    static ref CLOUDEVENTS_INVALID: IntCounter =
+        register_int_counter!("cloudevents_invalid", "number of CloudEvents refused for missing or invalid attributes").unwrap();
+}
+
+pub const SPEC_VERSION: &str = "1.0";
+const REQUIRED: [&str; 4] = ["id", "source", "specversion", "type"];
+const HTTP_PREFIX: &str = "ce-";
+const KAFKA_PREFIX: &str = "ce_";
+const STRUCTURED_CONTENT_TYPE: &str = "application/cloudevents+json";
+
+/// attribute name => value, as strings
+pub type Attributes = BTreeMap<String, String>;
+
+/// an event in a request
+#[derive(Debug, PartialEq)]
+pub struct Event {
+    pub attributes: Attributes,
+    /// a structured event's data, which replaces the body, as a binary
+    /// event's body is its data already
+    pub data: Option<Vec<u8>>,
+}
+
+/// The event in a request, None if it isn't one, or Err if it is one
+/// without valid attributes.
+pub fn from_request(headers: &HeaderMap, body: &[u8]) -> Result<Option<Event>, String> {
+    let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    let event = if content_type.to_ascii_lowercase().starts_with(STRUCTURED_CONTENT_TYPE) {
+        structured(body)?
+    } else {
+        let mut attributes = Attributes::new();
+        for (name, value) in headers {
+            if let Some(attribute) = name.as_str().strip_prefix(HTTP_PREFIX) {
+                let value = value.to_str().map_err(|_| format!("{} is not ASCII", name))?;
+                attributes.insert(attribute.to_owned(), value.to_owned());
+            }
+        }
+        if attributes.is_empty() {
+            return Ok(None);
+        }
+        if !content_type.is_empty() {
+            attributes.insert("datacontenttype".to_string(), content_type.to_owned());
+        }
+        Event { attributes, data: None }
+    };
+    CLOUDEVENTS_RECEIVED.inc();
+    validate(&event.attributes).inspect_err(|_| CLOUDEVENTS_INVALID.inc())?;
+    Ok(Some(event))
+}
+
+/// the attributes and data of an event in the JSON format
+fn structured(body: &[u8]) -> Result<Event, String> {
+    let Ok(Value::Object(mut event)) = serde_json::from_slice(body) else {
+        return Err("structured cloudevent is not a JSON object".to_string());
+    };
+    let data = match (event.remove("data"), event.remove("data_base64")) {
+        (_, Some(Value::String(encoded))) => {
+            Some(STANDARD.decode(encoded).map_err(|_| "cloudevent data_base64 is not base64".to_string())?)
+        }
+        (_, Some(_)) => return Err("cloudevent data_base64 is not a string".to_string()),
+        (None | Some(Value::Null), None) => None,
+        (Some(data), None) => {
+            // JSON, unless datacontenttype says otherwise
+            let content_type = event.entry("datacontenttype").or_insert(Value::String("application/json".to_string()));
+            match data {
+                Value::String(s) if !content_type.as_str().is_some_and(is_json) => Some(s.into_bytes()),
+                data => Some(data.to_string().into_bytes()),
+            }
+        }
+    };
+    let mut attributes = Attributes::new();
+    for (name, value) in event {
+        match value {
+            Value::Null => (),
+            Value::String(s) => {
+                attributes.insert(name, s);
+            }
+            v => {
+                attributes.insert(name, v.to_string());
+            }
+        }
+    }
+    Ok(Event {
+        attributes,
+        data: Some(data.unwrap_or_default()),
+    })
+}
+
+/// whether data of a content type is written as JSON in a structured event
+fn is_json(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    media_type == "application/json" || media_type == "text/json" || media_type.ends_with("+json")
+}
+
+fn validate(attributes: &Attributes) -> Result<(), String> {
+    for name in REQUIRED {
+        if attributes.get(name).map_or(true, |v| v.is_empty()) {
+            return Err(format!("cloudevent has no {}", name));
+        }
+    }
+    if attributes["specversion"] != SPEC_VERSION {
+        return Err(format!("unsupported cloudevents specversion {}", attributes["specversion"]));
+    }
+    for name in attributes.keys() {
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit()) {
+            return Err(format!("invalid cloudevent attribute name {}", name));
+        }
+    }
+    Ok(())
+}
+
+/// an event for a request which wasn't one, from a route with a cloudevent-type
+pub fn generate(event_type: &str, source: &str) -> Attributes {
+    let id: u128 = rand::thread_rng().gen();
+    BTreeMap::from([
+        ("id".to_string(), format!("{:032x}", id)),
+        ("source".to_string(), source.to_owned()),
+        ("specversion".to_string(), SPEC_VERSION.to_string()),
+        ("type".to_string(), event_type.to_owned()),
+        ("time".to_string(), Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+    ])
+}
+
+/// The Kafka headers for a request to a route: the attributes of the event
+/// it carries, or of an event generated for it, or none.  A structured
+/// event's body and content-type are replaced by its data and
+/// datacontenttype, as in binary mode.
+pub fn kafka_headers(
+    route: &Route,
+    path: &str,
+    headers: &mut HeaderMap,
+    body: &mut Bytes,
+) -> Result<Vec<(String, String)>, String> {
+    let attributes = match (from_request(headers, body)?, &route.cloudevent_type) {
+        (Some(event), _) => {
+            if let Some(data) = event.data {
+                *body = Bytes::from(data);
+                match event.attributes.get("datacontenttype") {
+                    Some(content_type) => {
+                        let value = HeaderValue::from_str(content_type)
+                            .map_err(|_| format!("invalid cloudevent datacontenttype {}", content_type))?;
+                        headers.insert(CONTENT_TYPE, value);
+                    }
+                    None => {
+                        headers.remove(CONTENT_TYPE);
+                    }
+                }
+            }
+            event.attributes
+        }
+        (None, Some(event_type)) => generate(event_type, path),
+        (None, None) => return Ok(Vec::new()),
+    };
+    Ok(attributes
+        .into_iter()
+        .map(|(name, value)| match name.as_str() {
+            "datacontenttype" => ("content-type".to_string(), value),
+            _ => (format!("{}{}", KAFKA_PREFIX, name), value),
+        })
+        .collect())
+}
+
+/// The attributes in a message's Kafka headers, named as in the binary HTTP
+/// mode, like `ce-id`, to pass to jobs with the request's headers.
+pub fn job_headers(kafka_headers: &[(String, String)]) -> Map<String, Value> {
+    let mut ret = Map::new();
+    for (name, value) in kafka_headers {
+        if let Some(attribute) = name.strip_prefix(KAFKA_PREFIX) {
+            ret.insert(format!("{}{}", HTTP_PREFIX, attribute), Value::String(value.clone()));
+        }
+    }
+    if !ret.is_empty() {
+        if let Some((_, content_type)) = kafka_headers.iter().find(|(n, _)| n == "content-type") {
+            ret.insert(format!("{}datacontenttype", HTTP_PREFIX), Value::String(content_type.clone()));
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse;
+    use hyper::header::HeaderName;
+
+    fn route(options: &str) -> Route {
+        let config = format!(r#"(("/t" . ((job-class . "T") (queue . "q") (topic . "t") {})))"#, options);
+        parse(&config).unwrap().0.remove("/t").unwrap()
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap()))
+            .collect()
+    }
+
+    const BINARY: [(&str, &str); 5] = [
+        ("ce-id", "1"),
+        ("ce-source", "/s"),
+        ("ce-specversion", "1.0"),
+        ("ce-type", "com.example.t"),
+        ("content-type", "application/json"),
+    ];
+
+    #[test]
+    fn binary() {
+        let event = from_request(&headers(&BINARY), b"{}").unwrap().unwrap();
+        assert_eq!(event.data, None);
+        assert_eq!(event.attributes["id"], "1");
+        assert_eq!(event.attributes["type"], "com.example.t");
+        assert_eq!(event.attributes["datacontenttype"], "application/json");
+        assert_eq!(from_request(&headers(&[("content-type", "application/json")]), b"{}"), Ok(None));
+    }
+
+    #[test]
+    fn structured() {
+        let structured = headers(&[("content-type", "application/cloudevents+json; charset=utf-8")]);
+        let event = from_request(
+            &structured,
+            br#"{"id": "1", "source": "/s", "specversion": "1.0", "type": "t", "seq": 3, "data": {"a": 1}}"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(event.data.as_deref(), Some(&br#"{"a":1}"#[..]));
+        assert_eq!(event.attributes["seq"], "3");
+        // JSON data is implied
+        assert_eq!(event.attributes["datacontenttype"], "application/json");
+        assert!(!event.attributes.contains_key("data"));
+
+        let event = from_request(
+            &structured,
+            br#"{"id": "1", "source": "/s", "specversion": "1.0", "type": "t", "datacontenttype": "text/plain", "data": "hi"}"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(event.data.as_deref(), Some(&b"hi"[..]));
+
+        let event = from_request(
+            &structured,
+            br#"{"id": "1", "source": "/s", "specversion": "1.0", "type": "t", "data_base64": "AAE="}"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(event.data.as_deref(), Some(&[0, 1][..]));
+        assert!(!event.attributes.contains_key("datacontenttype"));
+
+        assert!(from_request(&structured, b"[]").is_err());
+        assert!(from_request(&structured, br#"{"id": "1", "source": "/s", "specversion": "1.0", "type": "t", "data_base64": "!"}"#).is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        let missing = headers(&[("ce-id", "1"), ("ce-source", "/s"), ("ce-specversion", "1.0")]);
+        assert_eq!(from_request(&missing, b""), Err("cloudevent has no type".to_string()));
+        let empty = headers(&[("ce-id", ""), ("ce-source", "/s"), ("ce-specversion", "1.0"), ("ce-type", "t")]);
+        assert_eq!(from_request(&empty, b""), Err("cloudevent has no id".to_string()));
+        let version = headers(&[("ce-id", "1"), ("ce-source", "/s"), ("ce-specversion", "0.3"), ("ce-type", "t")]);
+        assert_eq!(from_request(&version, b""), Err("unsupported cloudevents specversion 0.3".to_string()));
+        let mut unnamed = headers(&BINARY);
+        unnamed.insert(HeaderName::from_static("ce-"), HeaderValue::from_static("x"));
+        assert_eq!(from_request(&unnamed, b""), Err("invalid cloudevent attribute name ".to_string()));
+    }
+
+    #[test]
+    fn generated() {
+        let attributes = generate("com.example.t", "/t");
+        assert_eq!(validate(&attributes), Ok(()));
+        assert_eq!(attributes["id"].len(), 32);
+        assert_eq!((attributes["type"].as_str(), attributes["source"].as_str()), ("com.example.t", "/t"));
+        assert_ne!(generate("com.example.t", "/t")["id"], attributes["id"]);
+    }
+
+    #[test]
+    fn kafka_headers_round_trip() {
+        let mut binary = headers(&BINARY);
+        let mut body = Bytes::from_static(b"{}");
+        let kafka = kafka_headers(&route(""), "/t", &mut binary, &mut body).unwrap();
+        assert!(kafka.contains(&("ce_id".to_string(), "1".to_string())));
+        assert!(kafka.contains(&("content-type".to_string(), "application/json".to_string())));
+        let job = job_headers(&kafka);
+        assert_eq!(job["ce-id"], "1");
+        assert_eq!(job["ce-specversion"], "1.0");
+        assert_eq!(job["ce-datacontenttype"], "application/json");
+        assert_eq!(job.len(), 5);
+
+        // a structured event is buffered as its data
+        let mut structured = headers(&[("content-type", "application/cloudevents+json")]);
+        let mut body = Bytes::from_static(
+            br#"{"id": "1", "source": "/s", "specversion": "1.0", "type": "t", "datacontenttype": "text/plain", "data": "hi"}"#,
+        );
+        let kafka = kafka_headers(&route(""), "/t", &mut structured, &mut body).unwrap();
+        assert_eq!(&body[..], b"hi");
+        assert_eq!(structured[CONTENT_TYPE], "text/plain");
+        assert!(kafka.contains(&("content-type".to_string(), "text/plain".to_string())));
+        assert_eq!(job_headers(&kafka)["ce-type"], "t");
+
+        let mut plain = headers(&[("content-type", "application/json")]);
+        let mut body = Bytes::from_static(b"{}");
+        assert_eq!(kafka_headers(&route(""), "/t", &mut plain, &mut body), Ok(Vec::new()));
+        let kafka = kafka_headers(&route(r#"(cloudevent-type . "com.example.t")"#), "/t", &mut plain, &mut body).unwrap();
+        assert_eq!(job_headers(&kafka)["ce-type"], "com.example.t");
+        assert_eq!(job_headers(&kafka)["ce-source"], "/t");
+        assert_eq!(job_headers(&[("content-type".to_string(), "text/plain".to_string())]), Map::new());
+    }
+}
@@ -0,0 +1,169 @@
+//! Compression of large bodies inside a BufferedRequest, since Kafka's lz4
+//! only applies to whole batches.  The codec is flagged in the request's
+//! bodyCompression field, and the consumer decompresses before jobs see it.
+
+use crate::buffered_http_request_capnp::buffered_request;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use std::io::{self, Read, Write};
+
+lazy_static! {
+    static ref COMPRESSED_BODIES: IntCounter =
+        register_int_counter!("compressed_bodies", "number of request bodies compressed before buffering").unwrap();
+}
+
+/// refuse to decompress bodies larger than this
+const MAX_BODY_BYTES: u64 = 64 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    Zstd,
+    Gzip,
+}
+
+/// a route's compression setting
+#[derive(Clone, Copy, Debug)]
+pub struct BodyCompression {
+    pub codec: Codec,
+    /// compress bodies larger than this many bytes
+    pub above: usize,
+}
+
+impl Codec {
+    pub fn from_name(name: &str) -> Option<Codec> {
+        match name {
+            "zstd" => Some(Codec::Zstd),
+            "gzip" => Some(Codec::Gzip),
+            _ => None,
+        }
+    }
+
+    fn compress(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::Zstd => zstd::encode_all(body, zstd::DEFAULT_COMPRESSION_LEVEL),
+            Codec::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    fn decompress(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let reader: Box<dyn Read> = match self {
+            Codec::Zstd => Box::new(zstd::Decoder::new(bytes)?),
+            Codec::Gzip => Box::new(GzDecoder::new(bytes)),
+        };
+        let mut body = Vec::new();
+        reader.take(MAX_BODY_BYTES + 1).read_to_end(&mut body)?;
+        if body.len() as u64 > MAX_BODY_BYTES {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "decompressed body over 64MiB"));
+        }
+        Ok(body)
+    }
+}
+
+/// Set the body, compressed if the route asks and it is large enough, and
+/// compression makes it smaller.
+pub fn set_body(req: &mut buffered_request::Builder, body: &[u8], o_compression: Option<&BodyCompression>) {
+    if let Some(compression) = o_compression.filter(|c| body.len() > c.above) {
+        if let Ok(compressed) = compression.codec.compress(body) {
+            if compressed.len() < body.len() {
+                COMPRESSED_BODIES.inc();
+                req.set_body(&compressed);
+                req.set_body_compression(match compression.codec {
+                    Codec::Zstd => buffered_request::Compression::Zstd,
+                    Codec::Gzip => buffered_request::Compression::Gzip,
+                });
+                return;
+            }
+        }
+    }
+    req.set_body(body);
+}
+
+/// the body as the producer received it
+pub fn get_body(req: &buffered_request::Reader) -> anyhow::Result<Vec<u8>> {
+    let body = req.get_body()?;
+    Ok(match req.get_body_compression()? {
+        buffered_request::Compression::None => body.to_vec(),
+        buffered_request::Compression::Zstd => Codec::Zstd.decompress(body)?,
+        buffered_request::Compression::Gzip => Codec::Gzip.decompress(body)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the stored body and its compression flag, and the body read back
+    fn set_and_get(body: &[u8], compression: Option<&BodyCompression>) -> (usize, buffered_request::Compression, Vec<u8>) {
+        let mut message = capnp::message::Builder::new_default();
+        let mut req = message.init_root::<buffered_request::Builder>();
+        set_body(&mut req, body, compression);
+        let reader = req.into_reader();
+        let stored = reader.get_body().unwrap().len();
+        (stored, reader.get_body_compression().unwrap(), get_body(&reader).unwrap())
+    }
+
+    #[test]
+    fn compresses_large_bodies() {
+        let body = "{\"id\": 1}".repeat(1000);
+        for codec in [Codec::Zstd, Codec::Gzip] {
+            let (stored, flag, read) = set_and_get(body.as_bytes(), Some(&BodyCompression { codec, above: 1024 }));
+            assert!(stored < body.len() / 10);
+            let expected = match codec {
+                Codec::Zstd => buffered_request::Compression::Zstd,
+                Codec::Gzip => buffered_request::Compression::Gzip,
+            };
+            assert_eq!(flag, expected);
+            assert_eq!(read, body.as_bytes());
+        }
+    }
+
+    #[test]
+    fn leaves_small_bodies() {
+        let zstd = BodyCompression {
+            codec: Codec::Zstd,
+            above: 1024,
+        };
+        let body = "{\"id\": 1}".repeat(100);
+        let (stored, flag, read) = set_and_get(body.as_bytes(), Some(&zstd));
+        assert_eq!((stored, flag), (body.len(), buffered_request::Compression::None));
+        assert_eq!(read, body.as_bytes());
+        let body = "{\"id\": 1}".repeat(1000);
+        let (_, flag, _) = set_and_get(body.as_bytes(), None);
+        assert_eq!(flag, buffered_request::Compression::None);
+    }
+
+    #[test]
+    fn leaves_bodies_compression_would_grow() {
+        // no repeats for the codec to find
+        let mut x: u32 = 1;
+        let body: Vec<u8> = (0..4096)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        let gzip = BodyCompression {
+            codec: Codec::Gzip,
+            above: 1024,
+        };
+        let (stored, flag, read) = set_and_get(&body, Some(&gzip));
+        assert_eq!((stored, flag), (body.len(), buffered_request::Compression::None));
+        assert_eq!(read, body);
+    }
+
+    #[test]
+    fn decompression_limit() {
+        let bomb = Codec::Zstd.compress(&vec![0; MAX_BODY_BYTES as usize + 1]).unwrap();
+        assert_eq!(Codec::Zstd.decompress(&bomb).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let under = Codec::Gzip.compress(&[0; 1024]).unwrap();
+        assert_eq!(Codec::Gzip.decompress(&under).unwrap(), vec![0; 1024]);
+    }
+}
@@ -2,23 +2,144 @@ use pest::Parser;
 use pest_derive::Parser;
 use pest::iterators::Pair;
 use std::collections::HashMap;
+use std::str::FromStr;
 use hyper::header::HeaderName;
+use serde_json::{Map, Value};
+use crate::body::JsonPath;
+use crate::compression::{BodyCompression, Codec};
+use crate::encoding::Encoding;
+use crate::headers::{Glob, HeaderCapture, HeaderRewrite, Template, TEMPLATE_VARS};
+use crate::job::RESERVED_KEYS;
+use crate::relay::Rejected;
+use crate::transform::{Action, BodyTransform, OnError, Pointer};
+use url::Url;
 
 #[derive(Parser)]
 #[grammar = "config.pest"]
 pub struct ConfigParser;
 
+/// shape of the job payload
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JobFormat {
+    /// a Sidekiq worker with args [body, headers]
+    Sidekiq,
+    /// an ActiveJob class, wrapped the way ActiveJob's Sidekiq adapter does
+    ActiveJob,
+}
+
+/// which job system the consumer writes to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SinkKind {
+    Sidekiq,
+    Celery,
+    BullMq,
+    Faktory,
+    /// POST the request to the route's upstream
+    Http,
+    /// XADD the request to the stream named by the route's queue
+    RedisStream,
+    /// append the request to files on local disk
+    Archive,
+}
+
+impl SinkKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SinkKind::Sidekiq => "sidekiq",
+            SinkKind::Celery => "celery",
+            SinkKind::BullMq => "bullmq",
+            SinkKind::Faktory => "faktory",
+            SinkKind::Http => "http",
+            SinkKind::RedisStream => "redis-stream",
+            SinkKind::Archive => "archive",
+        }
+    }
+
+    /// whether the sink reads a route attribute, so routes can't set one
+    /// which would be ignored
+    fn uses(&self, attribute: &str) -> bool {
+        use SinkKind::*;
+        match attribute {
+            "format" | "tags" | "extra" | "backtrace" | "dead" => *self == Sidekiq,
+            "retry" => matches!(self, Sidekiq | Faktory),
+            // job arguments and schedules
+            "body" | "jsonpath" | "delay" | "run-at-header" | "run-at-pointer" => matches!(self, Sidekiq | Celery | BullMq | Faktory),
+            "attempts" | "backoff" => *self == BullMq,
+            "upstream" | "rejected" => *self == Http,
+            "maxlen" | "dedupe" => *self == RedisStream,
+            // archived requests have no id
+            "jid-header" => *self != Archive,
+            _ => true,
+        }
+    }
+}
+
+/// how the request body becomes job arguments
+#[derive(Clone, Debug, PartialEq)]
+pub enum BodyMode {
+    /// the body as one string, which must be UTF-8
+    String,
+    /// the body parsed as JSON
+    Json,
+    /// the body as one base64 string, for binary payloads
+    Base64,
+    /// one argument for each field selected from the JSON body
+    JsonPath(Vec<JsonPath>),
+}
+
 #[derive(Clone, Debug)]
 pub struct Route {
+    /// Sidekiq class, Celery task name, BullMQ job name, or Faktory jobtype
     pub job_class: String,
     pub queue: String,
     pub topic: String,
     /// http headers to pass through kafka to Sidekiq
-    pub headers: Vec<HeaderName>,
+    pub headers: HeaderCapture,
+    /// header with a sender-assigned request id, used for the Sidekiq jid
+    pub jid_header: Option<HeaderName>,
+    /// extra top-level fields of the Sidekiq job, like retry and tags
+    pub job_fields: Map<String, Value>,
+    /// schedule jobs this many seconds after the request, which BullMQ
+    /// jobs get as their delay in milliseconds
+    pub delay_s: Option<u64>,
+    /// header with the time to run the job, in seconds since the epoch
+    pub run_at_header: Option<HeaderName>,
+    /// JSON pointer to the time to run the job, in seconds since the epoch
+    pub run_at_pointer: Option<String>,
+    pub format: JobFormat,
+    pub body: BodyMode,
+    pub sink: SinkKind,
+    /// BullMQ attempts
+    pub attempts: Option<i64>,
+    /// BullMQ backoff, like {"type": "exponential", "delay": 1000}
+    pub backoff: Option<Value>,
+    /// where the http sink sends requests
+    pub upstream: Option<Url>,
+    /// what the http sink does with requests the upstream rejects
+    pub rejected: Rejected,
+    /// trim the redis-stream sink's stream to about this many entries
+    pub maxlen: Option<usize>,
+    /// skip stream entries for offsets already written
+    pub dedupe: bool,
+    /// how requests are written to the buffer
+    pub encoding: Encoding,
+    /// make a CloudEvent of this type for requests which aren't one
+    pub cloudevent_type: Option<String>,
+    /// compress large bodies in the buffer
+    pub compression: Option<BodyCompression>,
+    /// give jobs the claim check for an offloaded body, rather than the body
+    pub pass_body_ref: bool,
+    /// undo the request's Content-Encoding before buffering it
+    pub decompress: bool,
+    /// rename captured headers, and set others
+    pub header_rewrite: HeaderRewrite,
+    /// drop, hash, mask, or rename fields of JSON bodies before buffering
+    pub transform: Option<BodyTransform>,
 }
 
+/// routes by path, and the paths with `{name}` segments, in config order
 #[derive(Clone, Debug)]
-pub struct Routes(pub HashMap<String, Route>);
+pub struct Routes(pub HashMap<String, Route>, pub Vec<String>);
 
 impl Routes {
     pub fn by_topic(self) -> HashMap<String, Route> {
@@ -28,6 +149,53 @@ impl Routes {
         }
         ret
     }
+
+    /// The route for a request path, and what its `{name}` segments matched.
+    /// Exact paths win over patterns, and patterns are tried in the order
+    /// they are in the config.
+    pub fn route_for(&self, path: &str) -> Option<(&Route, HashMap<String, String>)> {
+        if let Some(route) = self.0.get(path) {
+            return Some((route, HashMap::new()));
+        }
+        self.1
+            .iter()
+            .find_map(|pattern| Some((self.0.get(pattern)?, match_path(pattern, path)?)))
+    }
+}
+
+/// the segments of `path` matching each `{name}` in `pattern`
+fn match_path(pattern: &str, path: &str) -> Option<HashMap<String, String>> {
+    let (pattern, path): (Vec<_>, Vec<_>) = (pattern.split('/').collect(), path.split('/').collect());
+    if pattern.len() != path.len() {
+        return None;
+    }
+    let mut captures = HashMap::new();
+    for (p, segment) in pattern.into_iter().zip(path) {
+        match p.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+            Some(name) if !segment.is_empty() => {
+                captures.insert(name.to_owned(), segment.to_owned());
+            }
+            Some(_) => return None,
+            None if p == segment => (),
+            None => return None,
+        }
+    }
+    Some(captures)
+}
+
+/// the names of the `{name}` segments of a route's path
+fn path_captures(path: &str) -> Result<Vec<String>, String> {
+    let mut names: Vec<String> = Vec::new();
+    for segment in path.split('/').filter(|s| s.contains(['{', '}'])) {
+        match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+            Some(name) if name.is_empty() || name.contains(['{', '}']) => return Err(format!("invalid path capture {}", segment)),
+            Some(name) if TEMPLATE_VARS.contains(&name) => return Err(format!("path capture {{{}}} is a reserved name", name)),
+            Some(name) if names.iter().any(|n| n == name) => return Err(format!("duplicate path capture {{{}}}", name)),
+            Some(name) => names.push(name.to_owned()),
+            None => return Err(format!("path captures must be a whole segment, like /hooks/{{provider}}.  found {}", segment)),
+        }
+    }
+    Ok(names)
 }
 
 pub const DEFAULT_CONFIG_FILE: &str = "kafka_buffer.config";
@@ -35,6 +203,7 @@ pub const DEFAULT_CONFIG_FILE: &str = "kafka_buffer.config";
 /// the Vec will never be empty
 pub fn parse(s: &str) -> Result<Routes, Vec<String>> {
     let mut rules = HashMap::new();
+    let mut patterns = Vec::new();
     let mut errors = Vec::new();
     match ConfigParser::parse(Rule::config, s) {
         Err(err) => errors.push(format!(
@@ -83,10 +252,15 @@ pub fn parse(s: &str) -> Result<Routes, Vec<String>> {
                         errors.push(format!("{}: {} each route must end with an attribute set (a list of pairs).  found <{:?}>", line, col, attr_set.as_rule()));
                         continue;
                     }
-                    let mut class: Option<String> = None;
-                    let mut queue: Option<String> = None;
-                    let mut topic: Option<String> = None;
-                    let mut headers: Vec<HeaderName> = Vec::new();
+                    let captures = match path_captures(path.clone().into_inner().next().map(|p| p.as_str()).unwrap_or_default()) {
+                        Ok(captures) => captures,
+                        Err(err) => {
+                            let (line, col) = path.line_col();
+                            errors.push(format!("{}:{} {}", line, col, err));
+                            Vec::new()
+                        }
+                    };
+                    let mut attributes = Attributes::default();
                     for attr in attr_set.into_inner() {
                         if attr.as_rule() != Rule::pair {
                             let (line, col) = attr.line_col();
@@ -110,66 +284,15 @@ pub fn parse(s: &str) -> Result<Routes, Vec<String>> {
                             key.as_rule()
                         ));
                         }
-                        match key.as_str() {
-                            "job-class" => {
-                                expect_string(&value, &mut errors);
-                                match class {
-                                    None => class = value.into_inner().next().map(|v| v.as_str().to_owned()),
-                                    Some(_) => errors.push(error_duplicate(&key, "job-class")),
-                                }
-                            },
-                            "queue" => {
-                                expect_string(&value, &mut errors);
-                                match queue {
-                                    None => queue = value.into_inner().next().map(|v| v.as_str().to_owned()),
-                                    Some(_) => errors.push(error_duplicate(&key, "queue")),
-                                }
-                            },
-                            "topic" => {
-                                expect_string(&value, &mut errors);
-                                match topic {
-                                    None => topic = value.into_inner().next().map(|v| v.as_str().to_owned()),
-                                    Some(_) => errors.push(error_duplicate(&key, "topic")),
-                                }
-                            },
-                            "headers" => {
-                                if value.as_rule() != Rule::list {
-                                    let (line, col) = value.line_col();
-                                    errors.push(format!("{}:{} headers must be a list of strings found<{:?}>", line, col, value.as_rule()));
-                                }
-                                for h in value.into_inner() {
-                                    if h.as_rule() != Rule::string {
-                                        let (line, col) = h.line_col();
-                                        errors.push(format!("{}: {} each header must be a string.  found {}", line, col, h.as_str()));
-                                    }
-                                    let (line, col) = h.line_col();
-                                    let s = h.into_inner().next().unwrap().as_str();
-                                    match HeaderName::from_bytes(s.as_bytes()) {
-                                        Ok(header_name) => headers.push(header_name),
-                                        Err(_) => errors.push(format!("{}:{} invalid header name {}", line, col, s)),
-                                    }
-                                }
-                            },
-                            k => {
-                                let (line, col) = key.line_col();
-                                errors.push(format!(
-                                    "{}:{} valid attributes are job-class, queue, topic, headers.  got {}",
-                                    line, col, k
-                                ));
-                            }
-                        }
+                        attributes.set(&key, value, &captures, &mut errors);
                     }
-                    if let (Some(c), Some(q)) = (class, queue) {
-                        let topic = topic.unwrap_or(format!("{}__{}", q, c));
-                        rules.insert(
-                            path.into_inner().next().unwrap().as_str().to_owned(),
-                            Route {
-                                job_class: c,
-                                queue: q,
-                                topic,
-                                headers,
-                            },
-                        );
+                    let (line, col) = path.line_col();
+                    if let Some(route) = attributes.into_route(line, col, &mut errors) {
+                        let path = path.into_inner().next().unwrap().as_str().to_owned();
+                        if path.contains('{') && !patterns.contains(&path) {
+                            patterns.push(path.clone());
+                        }
+                        rules.insert(path, route);
                     }
                 }
             }
@@ -179,12 +302,552 @@ pub fn parse(s: &str) -> Result<Routes, Vec<String>> {
         }
     }
     if errors.len() == 0 {
-        Ok(Routes(rules))
+        Ok(Routes(rules, patterns))
     } else {
         Err(errors)
     }
 }
 
+/// every route attribute
+const ATTRIBUTES: &[&str] = &[
+    "job-class", "task", "queue", "topic", "headers", "jid-header", "retry", "backtrace", "dead", "tags", "extra", "delay",
+    "run-at-header", "run-at-pointer", "format", "body", "jsonpath", "sink", "job-name", "attempts", "backoff", "jobtype",
+    "upstream", "rejected", "maxlen", "dedupe", "encoding", "cloudevent-type", "compression", "compress-above",
+    "claim-check", "decompress", "rename-headers", "set-headers", "drop-fields", "hash-fields", "mask-fields",
+    "rename-fields", "transform-errors",
+];
+
+/// the attributes of one route, as they're parsed
+#[derive(Default)]
+struct Attributes {
+    class: Option<String>,
+    queue: Option<String>,
+    topic: Option<String>,
+    headers: HeaderCapture,
+    jid_header: Option<HeaderName>,
+    job_fields: Map<String, Value>,
+    delay_s: Option<u64>,
+    run_at_header: Option<HeaderName>,
+    run_at_pointer: Option<String>,
+    format: Option<JobFormat>,
+    /// a JsonPath has no paths here, into_route adds the jsonpath attribute's
+    body: Option<BodyMode>,
+    sink: Option<SinkKind>,
+    attempts: Option<i64>,
+    backoff: Option<Value>,
+    jsonpaths: Option<Vec<JsonPath>>,
+    upstream: Option<Url>,
+    rejected: Option<Rejected>,
+    maxlen: Option<usize>,
+    dedupe: Option<bool>,
+    encoding: Option<Encoding>,
+    cloudevent_type: Option<String>,
+    codec: Option<Codec>,
+    compress_above: Option<usize>,
+    pass_body_ref: Option<bool>,
+    decompress: Option<bool>,
+    header_rewrite: HeaderRewrite,
+    seen_rename: bool,
+    seen_set: bool,
+    transform_steps: Vec<(Pointer, Action)>,
+    seen_transforms: Vec<String>,
+    on_error: Option<OnError>,
+    /// each attribute's key and position, to check the sink uses it
+    given: Vec<(String, (usize, usize))>,
+}
+
+impl Attributes {
+    fn set(&mut self, key: &Pair<Rule>, value: Pair<Rule>, captures: &[String], errors: &mut Vec<String>) {
+        self.given.push((key.as_str().to_owned(), key.line_col()));
+        match key.as_str() {
+            "job-class" | "task" | "job-name" | "jobtype" => string(&mut self.class, key, value, "job-class, task, job-name, or jobtype", errors),
+            "queue" => string(&mut self.queue, key, value, "queue", errors),
+            "topic" => string(&mut self.topic, key, value, "topic", errors),
+            "cloudevent-type" => string(&mut self.cloudevent_type, key, value, "cloudevent-type", errors),
+            "headers" => self.headers(value, errors),
+            "jid-header" => header(&mut self.jid_header, key, value, errors),
+            "run-at-header" => header(&mut self.run_at_header, key, value, errors),
+            "delay" => number(&mut self.delay_s, key, &value, 0, "a number of seconds", errors),
+            "run-at-pointer" => self.run_at_pointer(key, value, errors),
+            "format" => choice(&mut self.format, key, value, &[("sidekiq", JobFormat::Sidekiq), ("activejob", JobFormat::ActiveJob)], errors),
+            "sink" => choice(
+                &mut self.sink,
+                key,
+                value,
+                &[
+                    ("sidekiq", SinkKind::Sidekiq),
+                    ("celery", SinkKind::Celery),
+                    ("bullmq", SinkKind::BullMq),
+                    ("faktory", SinkKind::Faktory),
+                    ("http", SinkKind::Http),
+                    ("redis-stream", SinkKind::RedisStream),
+                    ("archive", SinkKind::Archive),
+                ],
+                errors,
+            ),
+            "upstream" => self.upstream(key, value, errors),
+            "rejected" => choice(&mut self.rejected, key, value, &[("retry", Rejected::Retry), ("skip", Rejected::Skip)], errors),
+            "encoding" => choice(
+                &mut self.encoding,
+                key,
+                value,
+                &[
+                    ("capnp", Encoding::Capnp),
+                    ("capnp-packed", Encoding::CapnpPacked),
+                    ("json", Encoding::Json),
+                    ("protobuf", Encoding::Protobuf),
+                    // registered when the producer starts
+                    ("avro", Encoding::Avro { schema_id: 0 }),
+                ],
+                errors,
+            ),
+            "compression" => choice(&mut self.codec, key, value, &[("zstd", Codec::Zstd), ("gzip", Codec::Gzip)], errors),
+            "compress-above" => number(&mut self.compress_above, key, &value, 0, "a number of bytes", errors),
+            "claim-check" => choice(&mut self.pass_body_ref, key, value, &[("fetch", false), ("pass", true)], errors),
+            "decompress" => boolean(&mut self.decompress, key, &value, errors),
+            "rename-headers" => self.rename_headers(key, value, errors),
+            "set-headers" => self.set_headers(key, value, captures, errors),
+            "drop-fields" | "hash-fields" | "mask-fields" => self.transform_fields(key, value, errors),
+            "rename-fields" => self.rename_fields(key, value, errors),
+            "transform-errors" => choice(&mut self.on_error, key, value, &[("reject", OnError::Reject), ("pass", OnError::Pass)], errors),
+            "maxlen" => number(&mut self.maxlen, key, &value, 1, "a positive number", errors),
+            "dedupe" => boolean(&mut self.dedupe, key, &value, errors),
+            "attempts" => number(&mut self.attempts, key, &value, 1, "a positive number", errors),
+            "backoff" => self.backoff(key, value, errors),
+            "body" => choice(
+                &mut self.body,
+                key,
+                value,
+                &[
+                    ("string", BodyMode::String),
+                    ("json", BodyMode::Json),
+                    ("base64", BodyMode::Base64),
+                    ("jsonpath", BodyMode::JsonPath(Vec::new())),
+                ],
+                errors,
+            ),
+            "jsonpath" => self.jsonpath(key, value, errors),
+            "retry" | "backtrace" => self.job_field(key, value, &[Rule::boolean, Rule::integer], "true, false, or a number", errors),
+            "dead" => self.job_field(key, value, &[Rule::boolean], "true or false", errors),
+            "tags" => self.tags(key, value, errors),
+            "extra" => self.extra(value, errors),
+            k => {
+                let (line, col) = key.line_col();
+                errors.push(format!("{}:{} valid attributes are {}.  got {}", line, col, ATTRIBUTES.join(", "), k));
+            }
+        }
+    }
+
+    // ("content-type" "x-shopify-*"), or (all-except "x-internal-*")
+    fn headers(&mut self, value: Pair<Rule>, errors: &mut Vec<String>) {
+        let headers = &mut self.headers;
+        let (line, col) = value.line_col();
+        let items: Vec<Pair<Rule>> = match value.as_rule() {
+            Rule::ident => vec![value],
+            Rule::list => value.into_inner().collect(),
+            r => {
+                errors.push(format!("{}:{} headers must be a list of strings, or all-except.  found <{:?}>", line, col, r));
+                Vec::new()
+            }
+        };
+        for (i, h) in items.into_iter().enumerate() {
+            let (line, col) = h.line_col();
+            match h.as_rule() {
+                Rule::ident if i == 0 && h.as_str() == "all-except" => headers.all_except = Some(Vec::new()),
+                Rule::string => {
+                    let s = h.into_inner().next().map(|v| v.as_str()).unwrap_or_default();
+                    if headers.all_except.is_none() && !s.contains(['*', '?']) {
+                        match HeaderName::from_bytes(s.as_bytes()) {
+                            Ok(header_name) => headers.names.push(header_name),
+                            Err(_) => errors.push(format!("{}:{} invalid header name {}", line, col, s)),
+                        }
+                        continue;
+                    }
+                    match (Glob::parse(s), headers.all_except.as_mut()) {
+                        (Ok(glob), Some(denied)) => denied.push(glob),
+                        (Ok(glob), None) => headers.globs.push(glob),
+                        (Err(err), _) => errors.push(format!("{}:{} {}", line, col, err)),
+                    }
+                }
+                _ => errors.push(format!("{}: {} each header must be a string.  found {}", line, col, h.as_str())),
+            }
+        }
+    }
+
+    fn run_at_pointer(&mut self, key: &Pair<Rule>, value: Pair<Rule>, errors: &mut Vec<String>) {
+        expect_string(&value, errors);
+        let (line, col) = value.line_col();
+        match (self.run_at_pointer.is_some(), value.into_inner().next().map(|v| v.as_str().to_owned())) {
+            (true, _) => errors.push(error_duplicate(key, "run-at-pointer")),
+            (false, Some(pointer)) if pointer.starts_with('/') => self.run_at_pointer = Some(pointer),
+            (false, Some(pointer)) => errors.push(format!("{}:{} run-at-pointer must be a JSON pointer starting with /.  found {}", line, col, pointer)),
+            (false, None) => (),
+        }
+    }
+
+    fn upstream(&mut self, key: &Pair<Rule>, value: Pair<Rule>, errors: &mut Vec<String>) {
+        expect_string(&value, errors);
+        let (line, col) = value.line_col();
+        match (self.upstream.is_some(), value.into_inner().next().map(|v| Url::parse(v.as_str()))) {
+            (true, _) => errors.push(error_duplicate(key, "upstream")),
+            (false, Some(Ok(url))) if url.scheme() == "http" => self.upstream = Some(url),
+            (false, Some(Ok(url))) => errors.push(format!("{}:{} upstream must be an http:// url.  found {}", line, col, url)),
+            (false, Some(Err(err))) => errors.push(format!("{}:{} invalid upstream: {}", line, col, err)),
+            (false, None) => (),
+        }
+    }
+
+    // ((x-shopify-topic . "x-event-type") ...)
+    fn rename_headers(&mut self, key: &Pair<Rule>, value: Pair<Rule>, errors: &mut Vec<String>) {
+        if self.seen_rename {
+            errors.push(error_duplicate(key, "rename-headers"));
+        }
+        self.seen_rename = true;
+        for (from, to) in header_pairs(value, "rename-headers", errors) {
+            if let Some(to) = header_name(to, errors) {
+                self.header_rewrite.rename.push((from, to));
+            }
+        }
+    }
+
+    // ((x-environment . "production") (x-source . "{provider}") ...)
+    fn set_headers(&mut self, key: &Pair<Rule>, value: Pair<Rule>, captures: &[String], errors: &mut Vec<String>) {
+        if self.seen_set {
+            errors.push(error_duplicate(key, "set-headers"));
+        }
+        self.seen_set = true;
+        for (name, template) in header_pairs(value, "set-headers", errors) {
+            expect_string(&template, errors);
+            let (line, col) = template.line_col();
+            let s = template.into_inner().next().map(|v| v.as_str()).unwrap_or_default();
+            match Template::parse(s, captures) {
+                Ok(template) => self.header_rewrite.set.push((name, template)),
+                Err(err) => errors.push(format!("{}:{} {}", line, col, err)),
+            }
+        }
+    }
+
+    /// each transform attribute may be given once
+    fn seen_transform(&mut self, key: &Pair<Rule>, errors: &mut Vec<String>) {
+        if self.seen_transforms.iter().any(|k| k == key.as_str()) {
+            errors.push(error_duplicate(key, key.as_str()));
+        }
+        self.seen_transforms.push(key.as_str().to_owned());
+    }
+
+    // JSON pointers, applied in the order they're listed
+    fn transform_fields(&mut self, key: &Pair<Rule>, value: Pair<Rule>, errors: &mut Vec<String>) {
+        self.seen_transform(key, errors);
+        let action = match key.as_str() {
+            "drop-fields" => Action::Drop,
+            "hash-fields" => Action::Hash,
+            _ => Action::Mask,
+        };
+        if value.as_rule() != Rule::list {
+            let (line, col) = value.line_col();
+            errors.push(format!("{}:{} {} must be a list of JSON pointers.  found <{:?}>", line, col, key.as_str(), value.as_rule()));
+        }
+        for p in value.into_inner() {
+            expect_string(&p, errors);
+            let (line, col) = p.line_col();
+            match p.into_inner().next().map(|v| Pointer::parse(v.as_str())) {
+                Some(Ok(pointer)) => self.transform_steps.push((pointer, action.clone())),
+                Some(Err(err)) => errors.push(format!("{}:{} {}", line, col, err)),
+                None => (),
+            }
+        }
+    }
+
+    // (("/customerId" . "customer_id") ...)
+    fn rename_fields(&mut self, key: &Pair<Rule>, value: Pair<Rule>, errors: &mut Vec<String>) {
+        self.seen_transform(key, errors);
+        if value.as_rule() != Rule::list {
+            let (line, col) = value.line_col();
+            errors.push(format!("{}:{} rename-fields must be a list of pairs (\"/pointer\" . \"key\").  found <{:?}>", line, col, value.as_rule()));
+        }
+        for field in value.into_inner() {
+            let (line, col) = field.line_col();
+            if field.as_rule() != Rule::pair {
+                errors.push(format!("{}:{} each rename-fields entry must be a pair (\"/pointer\" . \"key\").  found <{:?}>", line, col, field.as_rule()));
+                continue;
+            }
+            let mut pairs = field.into_inner();
+            let from = pairs.next().unwrap(); // every Rule::pair has two children
+            let to = pairs.next().unwrap();
+            expect_string(&from, errors);
+            expect_string(&to, errors);
+            let from = from.into_inner().next().map(|v| Pointer::parse(v.as_str()));
+            let to = to.into_inner().next().map(|v| v.as_str().to_owned());
+            match (from, to) {
+                (Some(Ok(pointer)), Some(to)) => self.transform_steps.push((pointer, Action::Rename(to))),
+                (Some(Err(err)), _) => errors.push(format!("{}:{} {}", line, col, err)),
+                _ => (),
+            }
+        }
+    }
+
+    fn backoff(&mut self, key: &Pair<Rule>, value: Pair<Rule>, errors: &mut Vec<String>) {
+        let (line, col) = value.line_col();
+        if self.backoff.is_some() {
+            errors.push(error_duplicate(key, "backoff"));
+        }
+        match value.as_rule() {
+            // a fixed delay in milliseconds
+            Rule::integer => self.backoff = scalar(&value).map(|delay| serde_json::json!({"type": "fixed", "delay": delay})),
+            Rule::list => {
+                let mut options = Map::new();
+                for option in value.into_inner() {
+                    let (line, col) = option.line_col();
+                    if option.as_rule() != Rule::pair {
+                        errors.push(format!("{}:{} each backoff option must be a pair (key . value). found <{:?}>", line, col, option.as_rule()));
+                        continue;
+                    }
+                    let mut pairs = option.into_inner();
+                    let option_key = pairs.next().unwrap(); // every Rule::pair has two children
+                    let option_value = pairs.next().unwrap();
+                    match (option_key.as_str(), scalar(&option_value)) {
+                        ("type", Some(v @ Value::String(_))) | ("delay", Some(v @ Value::Number(_))) => {
+                            options.insert(option_key.as_str().to_owned(), v);
+                        }
+                        (k, _) => errors.push(format!("{}:{} backoff options are (type . \"fixed\" or \"exponential\") and (delay . milliseconds).  got {}", line, col, k)),
+                    }
+                }
+                self.backoff = Some(Value::Object(options));
+            }
+            r => errors.push(format!("{}:{} backoff must be milliseconds or a list of pairs.  found <{:?}>", line, col, r)),
+        }
+    }
+
+    fn jsonpath(&mut self, key: &Pair<Rule>, value: Pair<Rule>, errors: &mut Vec<String>) {
+        if self.jsonpaths.is_some() {
+            errors.push(error_duplicate(key, "jsonpath"));
+        }
+        if value.as_rule() != Rule::list {
+            let (line, col) = value.line_col();
+            errors.push(format!("{}:{} jsonpath must be a list of strings found<{:?}>", line, col, value.as_rule()));
+        }
+        let mut paths = Vec::new();
+        for p in value.into_inner() {
+            expect_string(&p, errors);
+            let (line, col) = p.line_col();
+            if let Some(s) = p.into_inner().next() {
+                match JsonPath::parse(s.as_str()) {
+                    Ok(path) => paths.push(path),
+                    Err(err) => errors.push(format!("{}:{} {}", line, col, err)),
+                }
+            }
+        }
+        self.jsonpaths = Some(paths);
+    }
+
+    /// a Sidekiq job option, which must be one of `rules`
+    fn job_field(&mut self, key: &Pair<Rule>, value: Pair<Rule>, rules: &[Rule], what: &str, errors: &mut Vec<String>) {
+        let (line, col) = value.line_col();
+        match value.as_rule() {
+            r if rules.contains(&r) => insert_job_field(&mut self.job_fields, key, scalar(&value), errors),
+            r => errors.push(format!("{}:{} {} must be {}.  found <{:?}>", line, col, key.as_str(), what, r)),
+        }
+    }
+
+    fn tags(&mut self, key: &Pair<Rule>, value: Pair<Rule>, errors: &mut Vec<String>) {
+        if value.as_rule() != Rule::list {
+            let (line, col) = value.line_col();
+            errors.push(format!("{}:{} tags must be a list of strings found<{:?}>", line, col, value.as_rule()));
+        }
+        let mut tags = Vec::new();
+        for t in value.into_inner() {
+            expect_string(&t, errors);
+            tags.extend(scalar(&t));
+        }
+        insert_job_field(&mut self.job_fields, key, Some(Value::Array(tags)), errors);
+    }
+
+    fn extra(&mut self, value: Pair<Rule>, errors: &mut Vec<String>) {
+        if value.as_rule() != Rule::list {
+            let (line, col) = value.line_col();
+            errors.push(format!("{}:{} extra must be a list of pairs (key . value) found<{:?}>", line, col, value.as_rule()));
+        }
+        for field in value.into_inner() {
+            let (line, col) = field.line_col();
+            if field.as_rule() != Rule::pair {
+                errors.push(format!("{}:{} each extra field must be a pair (key . value). found <{:?}>", line, col, field.as_rule()));
+                continue;
+            }
+            let mut pairs = field.into_inner();
+            let field_key = pairs.next().unwrap(); // every Rule::pair has two children
+            let field_value = pairs.next().unwrap();
+            if field_key.as_rule() != Rule::ident {
+                errors.push(format!("{}:{} each extra field must begin with an unquoted key.  found <{:?}>", line, col, field_key.as_rule()));
+            } else if RESERVED_KEYS.contains(&field_key.as_str()) {
+                errors.push(format!("{}:{} extra field {} is set by kafka-buffer", line, col, field_key.as_str()));
+            } else {
+                match scalar(&field_value) {
+                    None => errors.push(format!("{}:{} extra field must be a string, number, true, or false.  found <{:?}>", line, col, field_value.as_rule())),
+                    v => insert_job_field(&mut self.job_fields, &field_key, v, errors),
+                }
+            }
+        }
+    }
+
+    /// The route, checking the attributes against each other.  None if it
+    /// lacks a job class or queue.
+    fn into_route(mut self, line: usize, col: usize, errors: &mut Vec<String>) -> Option<Route> {
+        // renaming a header captures it
+        for (from, _) in &self.header_rewrite.rename {
+            if !self.headers.captures(from) {
+                self.headers.names.push(from.clone());
+            }
+        }
+        // the consumer can only see headers which pass through kafka
+        for h in self.jid_header.iter().chain(self.run_at_header.iter()) {
+            if !self.headers.captures(h) {
+                self.headers.names.push(h.clone());
+            }
+        }
+        let body = match (self.body, self.jsonpaths) {
+            (Some(BodyMode::JsonPath(_)), Some(paths)) => BodyMode::JsonPath(paths),
+            (Some(BodyMode::JsonPath(_)), None) => {
+                errors.push(format!("{}:{} body jsonpath needs a jsonpath attribute", line, col));
+                BodyMode::String
+            }
+            (_, Some(_)) => {
+                errors.push(format!("{}:{} jsonpath attribute needs (body . \"jsonpath\")", line, col));
+                BodyMode::String
+            }
+            (body, None) => body.unwrap_or(BodyMode::String),
+        };
+        let sink = self.sink.unwrap_or(SinkKind::Sidekiq);
+        for (k, (line, col)) in self.given.iter().filter(|(k, _)| !sink.uses(k)) {
+            errors.push(format!("{}:{} sink {} doesn't use the {} attribute", line, col, sink.name(), k));
+        }
+        if sink == SinkKind::Http && self.upstream.is_none() {
+            errors.push(format!("{}:{} sink http needs an upstream attribute", line, col));
+        }
+        if self.compress_above.is_some() && self.codec.is_none() {
+            errors.push(format!("{}:{} compress-above needs a compression attribute", line, col));
+        }
+        if self.codec.is_some() && !matches!(self.encoding, None | Some(Encoding::Capnp | Encoding::CapnpPacked)) {
+            errors.push(format!("{}:{} compression needs encoding capnp or capnp-packed", line, col));
+        }
+        let compression = self.codec.map(|codec| BodyCompression {
+            codec,
+            // bodies smaller than this rarely shrink enough to pay for it
+            above: self.compress_above.unwrap_or(1024),
+        });
+        if self.on_error.is_some() && self.transform_steps.is_empty() {
+            errors.push(format!("{}:{} transform-errors needs drop-fields, hash-fields, mask-fields, or rename-fields", line, col));
+        }
+        let transform = match self.transform_steps.is_empty() {
+            true => None,
+            false => Some(BodyTransform {
+                steps: self.transform_steps,
+                // rather than buffer what was meant to be kept out
+                on_error: self.on_error.unwrap_or(OnError::Reject),
+            }),
+        };
+        // http, stream, and archive routes have no job, so name their topic instead
+        let (class, queue) = match sink {
+            SinkKind::Http | SinkKind::RedisStream | SinkKind::Archive if self.topic.is_none() => {
+                errors.push(format!("{}:{} sinks http, redis-stream, and archive need a topic attribute", line, col));
+                (self.class, self.queue)
+            }
+            SinkKind::Http | SinkKind::Archive => (Some(self.class.unwrap_or_default()), Some(self.queue.unwrap_or_default())),
+            SinkKind::RedisStream => (Some(self.class.unwrap_or_default()), self.queue),
+            _ => (self.class, self.queue),
+        };
+        let (job_class, queue) = (class?, queue?);
+        Some(Route {
+            topic: self.topic.unwrap_or(format!("{}__{}", queue, job_class)),
+            job_class,
+            queue,
+            headers: self.headers,
+            jid_header: self.jid_header,
+            job_fields: self.job_fields,
+            delay_s: self.delay_s,
+            run_at_header: self.run_at_header,
+            run_at_pointer: self.run_at_pointer,
+            format: self.format.unwrap_or(JobFormat::Sidekiq),
+            body,
+            sink,
+            attempts: self.attempts,
+            backoff: self.backoff,
+            upstream: self.upstream,
+            // nothing is dropped unless the route says so
+            rejected: self.rejected.unwrap_or(Rejected::Retry),
+            maxlen: self.maxlen,
+            dedupe: self.dedupe.unwrap_or(false),
+            encoding: self.encoding.unwrap_or(Encoding::Capnp),
+            cloudevent_type: self.cloudevent_type,
+            compression,
+            pass_body_ref: self.pass_body_ref.unwrap_or(false),
+            decompress: self.decompress.unwrap_or(true),
+            header_rewrite: self.header_rewrite,
+            transform,
+        })
+    }
+}
+
+fn string(slot: &mut Option<String>, key: &Pair<Rule>, value: Pair<Rule>, name: &str, errors: &mut Vec<String>) {
+    expect_string(&value, errors);
+    match slot {
+        None => *slot = value.into_inner().next().map(|v| v.as_str().to_owned()),
+        Some(_) => errors.push(error_duplicate(key, name)),
+    }
+}
+
+fn header(slot: &mut Option<HeaderName>, key: &Pair<Rule>, value: Pair<Rule>, errors: &mut Vec<String>) {
+    match slot {
+        None => *slot = header_name(value, errors),
+        Some(_) => errors.push(error_duplicate(key, key.as_str())),
+    }
+}
+
+/// a whole number, at least `min`
+fn number<T: FromStr + PartialOrd>(slot: &mut Option<T>, key: &Pair<Rule>, value: &Pair<Rule>, min: T, what: &str, errors: &mut Vec<String>) {
+    let (line, col) = value.line_col();
+    match (slot.is_some(), value.as_rule(), value.as_str().parse::<T>()) {
+        (true, _, _) => errors.push(error_duplicate(key, key.as_str())),
+        (false, Rule::integer, Ok(n)) if n >= min => *slot = Some(n),
+        _ => errors.push(format!("{}:{} {} must be {}.  found {}", line, col, key.as_str(), what, value.as_str())),
+    }
+}
+
+fn boolean(slot: &mut Option<bool>, key: &Pair<Rule>, value: &Pair<Rule>, errors: &mut Vec<String>) {
+    let (line, col) = value.line_col();
+    match (slot.is_some(), value.as_rule()) {
+        (true, _) => errors.push(error_duplicate(key, key.as_str())),
+        (false, Rule::boolean) => *slot = Some(value.as_str() == "true"),
+        (false, r) => errors.push(format!("{}:{} {} must be true or false.  found <{:?}>", line, col, key.as_str(), r)),
+    }
+}
+
+/// a string naming one of `choices`
+fn choice<T: Clone>(slot: &mut Option<T>, key: &Pair<Rule>, value: Pair<Rule>, choices: &[(&str, T)], errors: &mut Vec<String>) {
+    expect_string(&value, errors);
+    let (line, col) = value.line_col();
+    match (slot.is_some(), value.into_inner().next().map(|v| v.as_str())) {
+        (true, _) => errors.push(error_duplicate(key, key.as_str())),
+        (false, Some(s)) => match choices.iter().find(|(name, _)| *name == s) {
+            Some((_, chosen)) => *slot = Some(chosen.clone()),
+            None => {
+                let names: Vec<&str> = choices.iter().map(|(name, _)| *name).collect();
+                errors.push(format!("{}:{} {} must be {}.  found {}", line, col, key.as_str(), one_of(&names), s));
+            }
+        },
+        (false, None) => (),
+    }
+}
+
+/// "a", "a or b", or "a, b, or c"
+fn one_of(names: &[&str]) -> String {
+    match names {
+        [] => String::new(),
+        [name] => name.to_string(),
+        [a, b] => format!("{} or {}", a, b),
+        [rest @ .., last] => format!("{}, or {}", rest.join(", "), last),
+    }
+}
+
 fn expect_string(value: &Pair<Rule>, errors: &mut Vec<String>) {
     if value.as_rule() != Rule::string {
         let (line, col) = value.line_col();
@@ -197,6 +860,63 @@ fn expect_string(value: &Pair<Rule>, errors: &mut Vec<String>) {
     }
 }
 
+fn header_name(value: Pair<Rule>, errors: &mut Vec<String>) -> Option<HeaderName> {
+    expect_string(&value, errors);
+    let (line, col) = value.line_col();
+    let s = value.into_inner().next()?.as_str();
+    match HeaderName::from_bytes(s.as_bytes()) {
+        Ok(header_name) => Some(header_name),
+        Err(_) => {
+            errors.push(format!("{}:{} invalid header name {}", line, col, s));
+            None
+        }
+    }
+}
+
+/// (header name . value) pairs, keyed by unquoted header names
+fn header_pairs<'a>(value: Pair<'a, Rule>, attribute: &str, errors: &mut Vec<String>) -> Vec<(HeaderName, Pair<'a, Rule>)> {
+    let (line, col) = value.line_col();
+    if value.as_rule() != Rule::list {
+        errors.push(format!("{}:{} {} must be a list of pairs (header . \"value\") found <{:?}>", line, col, attribute, value.as_rule()));
+        return Vec::new();
+    }
+    let mut ret = Vec::new();
+    for field in value.into_inner() {
+        let (line, col) = field.line_col();
+        if field.as_rule() != Rule::pair {
+            errors.push(format!("{}:{} each {} entry must be a pair (header . \"value\"). found <{:?}>", line, col, attribute, field.as_rule()));
+            continue;
+        }
+        let mut pairs = field.into_inner();
+        let name = pairs.next().unwrap(); // every Rule::pair has two children
+        let value = pairs.next().unwrap();
+        match (name.as_rule(), HeaderName::from_bytes(name.as_str().as_bytes())) {
+            (Rule::ident, Ok(header_name)) => ret.push((header_name, value)),
+            (Rule::ident, Err(_)) => errors.push(format!("{}:{} invalid header name {}", line, col, name.as_str())),
+            (r, _) => errors.push(format!("{}:{} each {} entry must begin with an unquoted header name.  found <{:?}>", line, col, attribute, r)),
+        }
+    }
+    ret
+}
+
+/// the JSON equivalent of a string, integer, or boolean
+fn scalar(value: &Pair<Rule>) -> Option<Value> {
+    match value.as_rule() {
+        Rule::string => value.clone().into_inner().next().map(|v| Value::String(v.as_str().to_owned())),
+        Rule::integer => value.as_str().parse::<i64>().ok().map(Value::from),
+        Rule::boolean => Some(Value::Bool(value.as_str() == "true")),
+        _ => None,
+    }
+}
+
+fn insert_job_field(job_fields: &mut Map<String, Value>, key: &Pair<Rule>, o_value: Option<Value>, errors: &mut Vec<String>) {
+    if job_fields.contains_key(key.as_str()) {
+        errors.push(error_duplicate(key, key.as_str()));
+    } else if let Some(value) = o_value {
+        job_fields.insert(key.as_str().to_owned(), value);
+    }
+}
+
 fn error_duplicate(key: &Pair<Rule>, name: &str) -> String {
     let (line, col) = key.line_col();
     format!("{}:{} duplicate attribute {}", line, col, name)
@@ -215,3 +935,112 @@ pub fn parse_from_file(config_file_name: &str) -> Routes {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(attributes: &str) -> Vec<String> {
+        match parse(&format!(r#"(("/t" . ({})))"#, attributes)) {
+            Ok(_) => Vec::new(),
+            Err(errors) => errors,
+        }
+    }
+
+    #[test]
+    fn attributes_the_sink_uses() {
+        assert_eq!(errors(r#"(job-class . "T") (queue . "q") (format . "activejob") (tags . ("a")) (retry . 3) (delay . 5)"#), Vec::<String>::new());
+        assert_eq!(errors(r#"(sink . "faktory") (jobtype . "T") (queue . "q") (retry . 3) (run-at-header . "x-run-at")"#), Vec::<String>::new());
+        assert_eq!(errors(r#"(sink . "http") (upstream . "http://localhost/") (topic . "t") (jid-header . "x-request-id")"#), Vec::<String>::new());
+    }
+
+    #[test]
+    fn patterns_in_config_order() {
+        let config = r#"(("/{b}/x" . ((job-class . "B") (queue . "q")))
+                         ("/z/{a}" . ((job-class . "A") (queue . "q")))
+                         ("/z/y" . ((job-class . "Y") (queue . "q"))))"#;
+        let routes = parse(config).unwrap();
+        assert_eq!(routes.1, vec!["/{b}/x".to_string(), "/z/{a}".to_string()]);
+        let (route, captures) = routes.route_for("/z/x").unwrap();
+        assert_eq!((route.job_class.as_str(), captures["b"].as_str()), ("B", "z"));
+        let (route, captures) = routes.route_for("/z/w").unwrap();
+        assert_eq!((route.job_class.as_str(), captures["a"].as_str()), ("A", "w"));
+        // exact paths first
+        let (route, captures) = routes.route_for("/z/y").unwrap();
+        assert_eq!((route.job_class.as_str(), captures.len()), ("Y", 0));
+        assert!(routes.route_for("/y/w").is_none());
+    }
+
+    #[test]
+    fn body_modes() {
+        let body = |options: &str| {
+            let config = format!(r#"(("/t" . ((job-class . "T") (queue . "q") {})))"#, options);
+            parse(&config).map(|mut routes| routes.0.remove("/t").unwrap().body)
+        };
+        assert_eq!(body(""), Ok(BodyMode::String));
+        assert_eq!(body(r#"(body . "string")"#), Ok(BodyMode::String));
+        assert_eq!(body(r#"(body . "json")"#), Ok(BodyMode::Json));
+        assert_eq!(body(r#"(body . "base64")"#), Ok(BodyMode::Base64));
+        assert!(matches!(body(r#"(body . "jsonpath") (jsonpath . ("$.a" "$.b"))"#), Ok(BodyMode::JsonPath(paths)) if paths.len() == 2));
+        assert_eq!(body(r#"(body . "jsonpath")"#), Err(vec!["1:3 body jsonpath needs a jsonpath attribute".to_string()]));
+        assert_eq!(
+            body(r#"(body . "jsno")"#),
+            Err(vec!["1:51 body must be string, json, base64, or jsonpath.  found jsno".to_string()])
+        );
+    }
+
+    #[test]
+    fn attribute_values() {
+        let config = r#"(("/t" . ((job-class . "T") (queue . "q") (sink . "sidekiq") (delay . 30) (encoding . "capnp-packed")
+                                 (compression . "zstd") (compress-above . 10) (claim-check . "pass") (decompress . false)
+                                 (retry . 5) (jid-header . "x-request-id"))))"#;
+        let route = parse(config).unwrap().0.remove("/t").unwrap();
+        assert_eq!((route.topic.as_str(), route.delay_s, route.encoding), ("q__T", Some(30), Encoding::CapnpPacked));
+        assert_eq!(route.compression.map(|c| (c.codec, c.above)), Some((Codec::Zstd, 10)));
+        assert!(route.pass_body_ref && !route.decompress);
+        assert_eq!(route.job_fields["retry"], 5);
+        assert!(route.headers.captures(&HeaderName::from_static("x-request-id")));
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert_eq!(
+            errors(r#"(job-class . "T") (queue . "q") (sink . "resque") (delay . -1) (decompress . "no") (queue . "r")"#),
+            vec![
+                "1:51 sink must be sidekiq, celery, bullmq, faktory, http, redis-stream, or archive.  found resque".to_string(),
+                "1:70 delay must be a number of seconds.  found -1".to_string(),
+                "1:88 decompress must be true or false.  found <string>".to_string(),
+                "1:95 duplicate attribute queue".to_string(),
+            ]
+        );
+        assert_eq!(
+            errors(r#"(job-class . "T") (queue . "q") (colour . "blue")"#),
+            vec![format!("1:44 valid attributes are {}.  got colour", ATTRIBUTES.join(", "))]
+        );
+    }
+
+    #[test]
+    fn attributes_the_sink_ignores() {
+        assert_eq!(
+            errors(r#"(sink . "celery") (task . "t") (queue . "q") (format . "activejob") (retry . 3) (delay . 5)"#),
+            vec![
+                "1:57 sink celery doesn't use the format attribute".to_string(),
+                "1:80 sink celery doesn't use the retry attribute".to_string(),
+            ]
+        );
+        assert_eq!(
+            errors(r#"(sink . "archive") (topic . "t") (jid-header . "x-request-id") (body . "json")"#),
+            vec![
+                "1:45 sink archive doesn't use the jid-header attribute".to_string(),
+                "1:75 sink archive doesn't use the body attribute".to_string(),
+            ]
+        );
+        assert_eq!(
+            errors(r#"(job-class . "T") (queue . "q") (maxlen . 10) (upstream . "http://localhost/")"#),
+            vec![
+                "1:44 sink sidekiq doesn't use the maxlen attribute".to_string(),
+                "1:58 sink sidekiq doesn't use the upstream attribute".to_string(),
+            ]
+        );
+    }
+}
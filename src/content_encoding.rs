@@ -0,0 +1,177 @@
+//! Request bodies sent with a Content-Encoding, decoded by the producer so
+//! jobs get the body the sender meant.
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, CONTENT_ENCODING};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use std::io::{self, Read};
+
+lazy_static! {
+    static ref DECODED_BODIES: IntCounter =
+        register_int_counter!("content_encoding_decoded", "number of request bodies decoded from their Content-Encoding").unwrap();
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// an encoding we can't decode, for 415
+    Unsupported(String),
+    /// larger than the limit once decoded, for 413
+    TooLarge,
+    /// not what the header says, for 400
+    Invalid(io::Error),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Unsupported(encoding) => write!(f, "unsupported content-encoding {}", encoding),
+            DecodeError::TooLarge => write!(f, "decoded body too large"),
+            DecodeError::Invalid(err) => write!(f, "invalid encoded body: {}", err),
+        }
+    }
+}
+
+/// Read all of `reader`, or TooLarge if it has more than `max_size` bytes.
+fn read_limited(reader: impl Read, max_size: usize) -> Result<Vec<u8>, DecodeError> {
+    let mut decoded = Vec::new();
+    reader
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut decoded)
+        .map_err(DecodeError::Invalid)?;
+    if decoded.len() > max_size {
+        return Err(DecodeError::TooLarge);
+    }
+    Ok(decoded)
+}
+
+fn decode_one(encoding: &str, body: &[u8], max_size: usize) -> Result<Vec<u8>, DecodeError> {
+    match encoding {
+        "gzip" | "x-gzip" => read_limited(GzDecoder::new(body), max_size),
+        // deflate is meant to be zlib, but some senders use raw deflate
+        "deflate" => match read_limited(ZlibDecoder::new(body), max_size) {
+            Err(DecodeError::Invalid(_)) => read_limited(DeflateDecoder::new(body), max_size),
+            decoded => decoded,
+        },
+        "br" => read_limited(brotli::Decompressor::new(body, 4096), max_size),
+        "zstd" => read_limited(zstd::Decoder::new(body).map_err(DecodeError::Invalid)?, max_size),
+        _ => Err(DecodeError::Unsupported(encoding.to_owned())),
+    }
+}
+
+/// The body with its Content-Encodings undone, last applied first, and the
+/// header removed.  The decoded body is limited to `max_size`, like the
+/// body as received, so a small compressed body can't fill memory.
+pub fn decode_body(headers: &mut HeaderMap, body: Bytes, max_size: usize) -> Result<Bytes, DecodeError> {
+    let mut encodings = Vec::new();
+    for value in headers.get_all(CONTENT_ENCODING) {
+        let value = value.to_str().map_err(|_| DecodeError::Unsupported(format!("{:?}", value)))?;
+        encodings.extend(value.split(',').map(|e| e.trim().to_ascii_lowercase()).filter(|e| !e.is_empty()));
+    }
+    let mut decoded = body;
+    for encoding in encodings.iter().rev() {
+        if encoding != "identity" {
+            decoded = Bytes::from(decode_one(encoding, &decoded, max_size)?);
+        }
+    }
+    if !encodings.is_empty() {
+        headers.remove(CONTENT_ENCODING);
+        DECODED_BODIES.inc();
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use hyper::header::HeaderValue;
+    use std::io::Write;
+
+    fn deflate(body: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zlib(body: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn gzip(body: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn decode(encoding: &str, body: Vec<u8>, max_size: usize) -> Result<Bytes, DecodeError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_str(encoding).unwrap());
+        decode_body(&mut headers, Bytes::from(body), max_size)
+    }
+
+    #[test]
+    fn deflate_too_large() {
+        let body = vec![b'a'; 2048];
+        assert_eq!(decode("deflate", zlib(&body), 4096).unwrap(), body);
+        assert_eq!(decode("deflate", deflate(&body), 4096).unwrap(), body);
+        // not retried as raw deflate, which would call it invalid
+        assert!(matches!(decode("deflate", zlib(&body), 1024), Err(DecodeError::TooLarge)));
+        assert!(matches!(decode("deflate", deflate(&body), 1024), Err(DecodeError::TooLarge)));
+        assert!(matches!(decode("deflate", b"not deflate".to_vec(), 1024), Err(DecodeError::Invalid(_))));
+    }
+
+    #[test]
+    fn each_encoding() {
+        let body = b"{\"id\": 1}".repeat(10);
+        let mut br = Vec::new();
+        brotli::BrotliCompress(&mut &body[..], &mut br, &Default::default()).unwrap();
+        let encoded = [
+            ("gzip", gzip(&body)),
+            ("x-gzip", gzip(&body)),
+            ("deflate", zlib(&body)),
+            ("br", br),
+            ("zstd", zstd::encode_all(&body[..], 0).unwrap()),
+            ("identity", body.clone()),
+        ];
+        for (encoding, bytes) in encoded {
+            assert_eq!(decode(encoding, bytes, 1024).unwrap(), body, "{}", encoding);
+        }
+    }
+
+    #[test]
+    fn layers() {
+        let body = b"{\"id\": 1}".to_vec();
+        // applied gzip first, then zstd
+        let bytes = zstd::encode_all(&gzip(&body)[..], 0).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.append(CONTENT_ENCODING, HeaderValue::from_static("GZIP"));
+        headers.append(CONTENT_ENCODING, HeaderValue::from_static("identity, zstd"));
+        assert_eq!(decode_body(&mut headers, Bytes::from(bytes), 1024).unwrap(), body);
+        assert!(headers.get(CONTENT_ENCODING).is_none());
+        // in the wrong order
+        let bytes = zstd::encode_all(&gzip(&body)[..], 0).unwrap();
+        assert!(matches!(decode("zstd, gzip", bytes, 1024), Err(DecodeError::Invalid(_))));
+    }
+
+    #[test]
+    fn limits() {
+        let body = vec![0; 64 << 10];
+        assert!(matches!(decode("gzip", gzip(&body), body.len() - 1), Err(DecodeError::TooLarge)));
+        assert_eq!(decode("gzip", gzip(&body), body.len()).unwrap(), body);
+        // each layer is limited
+        let bytes = gzip(&gzip(&body));
+        assert!(matches!(decode("gzip, gzip", bytes, 1024), Err(DecodeError::TooLarge)));
+    }
+
+    #[test]
+    fn unsupported() {
+        assert!(matches!(decode("compress", b"x".to_vec(), 1024), Err(DecodeError::Unsupported(e)) if e == "compress"));
+        let mut headers = HeaderMap::new();
+        assert_eq!(decode_body(&mut headers, Bytes::from_static(b"x"), 1024).unwrap(), &b"x"[..]);
+    }
+}
@@ -0,0 +1,545 @@
+//! A buffer in a local directory, for running without Kafka on one host.
+//!
+//! Each topic is one partition, a directory of append-only segments named
+//! by the offset of their first message, like `buffer/foo_topic/
+//! 00000000000000000000.log`.  Each message is its length as a big-endian
+//! u32, then when it was written, in milliseconds since the epoch as a
+//! big-endian i64, the length of its headers as a big-endian u16, the
+//! headers as a JSON list of [name, value] pairs, and the payload.  The
+//! producer syncs each message to disk before acknowledging it, and the
+//! consumer stores the next offset to read in `offsets.<group id>` next to
+//! the segments.  Only one producer may write to a directory at a time.
+//! Both block on disk in block_in_place, so they need a multi-thread tokio
+//! runtime.
+
+use crate::buffer::{BufferConsumer, BufferProducer, Message, SendError};
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::*;
+
+/// how often the consumer looks for new messages
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+fn segment_path(dir: &Path, topic: &str, base: i64) -> PathBuf {
+    dir.join(topic).join(format!("{:020}.log", base))
+}
+
+/// the first offset of each segment of a topic, in order
+fn segment_bases(dir: &Path, topic: &str) -> io::Result<Vec<i64>> {
+    let mut bases = Vec::new();
+    match fs::read_dir(dir.join(topic)) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(bases),
+        Err(err) => return Err(err),
+        Ok(entries) => {
+            for entry in entries {
+                let name = entry?.file_name();
+                if let Some(base) = name.to_str().and_then(|n| n.strip_suffix(".log")).and_then(|n| n.parse().ok()) {
+                    bases.push(base);
+                }
+            }
+        }
+    }
+    bases.sort();
+    Ok(bases)
+}
+
+/// a message as written in a segment
+#[derive(Debug, PartialEq)]
+struct Frame {
+    timestamp_ms: i64,
+    headers: Vec<(String, String)>,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn encode(&self) -> io::Result<Vec<u8>> {
+        let headers = serde_json::to_vec(&self.headers)?;
+        let headers_len: u16 = headers
+            .len()
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "headers over 64KiB"))?;
+        let len: u32 = (8 + 2 + headers.len() + self.payload.len())
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message over 4GiB"))?;
+        let mut frame = Vec::with_capacity(4 + len as usize);
+        frame.extend(len.to_be_bytes());
+        frame.extend(self.timestamp_ms.to_be_bytes());
+        frame.extend(headers_len.to_be_bytes());
+        frame.extend(headers);
+        frame.extend(&self.payload);
+        Ok(frame)
+    }
+}
+
+/// Read the message at the file's position, leaving the position after it.
+/// None if the file ends before a whole message.
+fn read_message(file: &mut File) -> io::Result<Option<Frame>> {
+    let start = file.stream_position()?;
+    let available = file.metadata()?.len().saturating_sub(start);
+    if available < 4 {
+        return Ok(None);
+    }
+    let mut len = [0; 4];
+    file.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as u64;
+    if available < 4 + len {
+        file.seek(SeekFrom::Start(start))?;
+        return Ok(None);
+    }
+    let mut frame = vec![0; len as usize];
+    file.read_exact(&mut frame)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed message");
+    let [t0, t1, t2, t3, t4, t5, t6, t7, a, b, rest @ ..] = frame.as_slice() else {
+        return Err(invalid());
+    };
+    let timestamp_ms = i64::from_be_bytes([*t0, *t1, *t2, *t3, *t4, *t5, *t6, *t7]);
+    let headers_len = u16::from_be_bytes([*a, *b]) as usize;
+    if rest.len() < headers_len {
+        return Err(invalid());
+    }
+    let (headers, payload) = rest.split_at(headers_len);
+    let headers = serde_json::from_slice(headers).map_err(|_| invalid())?;
+    Ok(Some(Frame {
+        timestamp_ms,
+        headers,
+        payload: payload.to_vec(),
+    }))
+}
+
+/// the segment a producer appends to
+struct Tail {
+    file: File,
+    /// offset of the next message
+    next: i64,
+    bytes: u64,
+}
+
+pub struct DiskLogProducer {
+    dir: PathBuf,
+    /// start a new segment after this many bytes
+    segment_bytes: u64,
+    /// delete segments last written longer ago than this
+    retention: Duration,
+    tails: tokio::sync::Mutex<HashMap<String, Tail>>,
+}
+
+impl DiskLogProducer {
+    pub fn new(dir: &Path, segment_bytes: u64, retention: Duration) -> anyhow::Result<DiskLogProducer> {
+        crate::assert_multi_thread("DiskLogProducer");
+        fs::create_dir_all(dir)?;
+        Ok(DiskLogProducer {
+            dir: dir.to_owned(),
+            segment_bytes,
+            retention,
+            tails: tokio::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Open the last segment of a topic, dropping any message a crash left
+    /// half written.
+    fn open_tail(&self, topic: &str) -> io::Result<Tail> {
+        fs::create_dir_all(self.dir.join(topic))?;
+        let base = segment_bases(&self.dir, topic)?.last().copied().unwrap_or(0);
+        let path = segment_path(&self.dir, topic, base);
+        let mut file = File::options().create(true).read(true).append(true).open(&path)?;
+        let mut next = base;
+        while read_message(&mut file)?.is_some() {
+            next += 1;
+        }
+        let bytes = file.stream_position()?;
+        if bytes < file.metadata()?.len() {
+            warn!("truncating partial message at the end of {}", path.display());
+            file.set_len(bytes)?;
+            file.sync_data()?;
+        }
+        Ok(Tail { file, next, bytes })
+    }
+
+    fn append(
+        &self,
+        tails: &mut HashMap<String, Tail>,
+        topic: &str,
+        headers: &[(String, String)],
+        payload: &[u8],
+    ) -> io::Result<i64> {
+        if !tails.contains_key(topic) {
+            tails.insert(topic.to_owned(), self.open_tail(topic)?);
+        }
+        let tail = tails.get_mut(topic).expect("opened above");
+        if tail.bytes >= self.segment_bytes {
+            let path = segment_path(&self.dir, topic, tail.next);
+            tail.file = File::options().create_new(true).read(true).append(true).open(&path)?;
+            tail.bytes = 0;
+            info!("started segment {}", path.display());
+            self.remove_expired(topic, tail.next)?;
+        }
+        let frame = Frame {
+            timestamp_ms: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64,
+            headers: headers.to_vec(),
+            payload: payload.to_vec(),
+        }
+        .encode()?;
+        tail.file.write_all(&frame)?;
+        tail.file.sync_data()?;
+        tail.bytes += frame.len() as u64;
+        tail.next += 1;
+        Ok(tail.next - 1)
+    }
+
+    /// delete segments, other than the current one, past retention
+    fn remove_expired(&self, topic: &str, current: i64) -> io::Result<()> {
+        for base in segment_bases(&self.dir, topic)? {
+            let path = segment_path(&self.dir, topic, base);
+            let modified = fs::metadata(&path)?.modified()?;
+            let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+            if base != current && age > self.retention {
+                info!("removing expired segment {}", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BufferProducer for DiskLogProducer {
+    async fn send(&self, topic: &str, headers: &[(String, String)], payload: &[u8]) -> Result<(i32, i64), SendError> {
+        let mut tails = self.tails.lock().await;
+        match tokio::task::block_in_place(|| self.append(&mut tails, topic, headers, payload)) {
+            Ok(offset) => Ok((0, offset)),
+            Err(err) => {
+                // reopen, and find the end again, on the next send
+                tails.remove(topic);
+                Err(SendError::Failed(format!("{}: {}", topic, err)))
+            }
+        }
+    }
+}
+
+/// where the consumer is in one topic
+struct Reader {
+    topic: String,
+    /// the segment being read, positioned at the next message
+    segment: Option<File>,
+    /// offset of the next message
+    next: i64,
+}
+
+struct ConsumerState {
+    readers: Vec<Reader>,
+    /// reader to try first, so one busy topic can't starve the rest
+    turn: usize,
+    /// topics whose first message hasn't been received
+    assigned: HashSet<String>,
+}
+
+pub struct DiskLogConsumer {
+    dir: PathBuf,
+    group_id: String,
+    state: Mutex<ConsumerState>,
+}
+
+impl DiskLogConsumer {
+    /// Start each topic from the committed offset, or else from its first
+    /// segment.
+    pub fn new(dir: &Path, group_id: &str, topics: &[&str]) -> anyhow::Result<DiskLogConsumer> {
+        crate::assert_multi_thread("DiskLogConsumer");
+        let consumer = DiskLogConsumer {
+            dir: dir.to_owned(),
+            group_id: group_id.to_owned(),
+            state: Mutex::new(ConsumerState {
+                readers: Vec::new(),
+                turn: 0,
+                assigned: topics.iter().map(|t| t.to_string()).collect(),
+            }),
+        };
+        let mut readers = Vec::new();
+        for topic in topics {
+            let next = match fs::read_to_string(consumer.offsets_path(topic)) {
+                Ok(s) => s.trim().parse()?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    segment_bases(dir, topic)?.first().copied().unwrap_or(0)
+                }
+                Err(err) => return Err(err.into()),
+            };
+            readers.push(consumer.reader_at(topic, next)?);
+        }
+        consumer.state.lock().unwrap().readers = readers;
+        Ok(consumer)
+    }
+
+    fn offsets_path(&self, topic: &str) -> PathBuf {
+        self.dir.join(topic).join(format!("offsets.{}", self.group_id))
+    }
+
+    /// a reader positioned at `offset`, or at the first message kept
+    fn reader_at(&self, topic: &str, offset: i64) -> io::Result<Reader> {
+        let bases = segment_bases(&self.dir, topic)?;
+        let Some(base) = bases.iter().rev().find(|b| **b <= offset).or(bases.first()).copied() else {
+            return Ok(Reader { topic: topic.to_owned(), segment: None, next: offset });
+        };
+        if base > offset {
+            warn!("topic={} offset={} was removed, starting from offset={}", topic, offset, base);
+        }
+        let mut file = File::open(segment_path(&self.dir, topic, base))?;
+        let mut next = base;
+        while next < offset && read_message(&mut file)?.is_some() {
+            next += 1;
+        }
+        Ok(Reader { topic: topic.to_owned(), segment: Some(file), next })
+    }
+
+    /// the next message in a topic, if one has been written
+    fn try_read(&self, reader: &mut Reader) -> io::Result<Option<Message>> {
+        if reader.segment.is_none() {
+            // nothing was written when we started
+            match segment_bases(&self.dir, &reader.topic)?.first() {
+                Some(_) => *reader = self.reader_at(&reader.topic, reader.next)?,
+                None => return Ok(None),
+            }
+        }
+        let Some(file) = reader.segment.as_mut() else {
+            return Ok(None);
+        };
+        if let Some(frame) = read_message(file)? {
+            reader.next += 1;
+            return Ok(Some(Message {
+                topic: reader.topic.clone(),
+                partition: 0,
+                offset: reader.next - 1,
+                headers: frame.headers,
+                payload: frame.payload,
+                timestamp_ms: Some(frame.timestamp_ms),
+            }));
+        }
+        // the producer starts a new segment at our offset when this one is full
+        let next_path = segment_path(&self.dir, &reader.topic, reader.next);
+        if next_path.exists() {
+            reader.segment = Some(File::open(next_path)?);
+            return self.try_read(reader);
+        }
+        Ok(None)
+    }
+}
+
+#[async_trait]
+impl BufferConsumer for DiskLogConsumer {
+    async fn recv(&self) -> anyhow::Result<Message> {
+        loop {
+            let found = tokio::task::block_in_place(|| -> io::Result<Option<Message>> {
+                let mut state = self.state.lock().unwrap();
+                let n = state.readers.len();
+                for i in 0..n {
+                    let turn = (state.turn + i) % n;
+                    if let Some(message) = self.try_read(&mut state.readers[turn])? {
+                        state.turn = (turn + 1) % n;
+                        return Ok(Some(message));
+                    }
+                }
+                Ok(None)
+            })?;
+            if let Some(message) = found {
+                return Ok(message);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    fn take_assigned(&self, topic: &str, _partition: i32) -> bool {
+        self.state.lock().unwrap().assigned.remove(topic)
+    }
+
+    async fn seek(&self, topic: &str, _partition: i32, offset: i64) -> anyhow::Result<()> {
+        let reader = self.reader_at(topic, offset)?;
+        let mut state = self.state.lock().unwrap();
+        match state.readers.iter_mut().find(|r| r.topic == topic) {
+            Some(r) => *r = reader,
+            None => return Err(anyhow::anyhow!("not subscribed to topic {}", topic)),
+        }
+        Ok(())
+    }
+
+    /// Replace each offsets file, synced before the rename so a crash
+    /// leaves the old offset or the new one.
+    async fn commit(&self, offsets: &[(String, i32, i64)]) -> anyhow::Result<()> {
+        for (topic, _partition, next) in offsets {
+            let path = self.offsets_path(topic);
+            let tmp = self.dir.join(topic).join(format!("offsets.{}.tmp", self.group_id));
+            let mut file = File::create(&tmp)?;
+            file.write_all(next.to_string().as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&tmp, &path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("disklog-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn headers(value: &str) -> Vec<(String, String)> {
+        vec![("kafka-buffer-encoding".to_owned(), value.to_owned())]
+    }
+
+    async fn recv_payloads(consumer: &DiskLogConsumer, n: usize) -> Vec<(i64, Vec<u8>)> {
+        let mut ret = Vec::new();
+        for _ in 0..n {
+            let message = tokio::time::timeout(Duration::from_secs(5), consumer.recv()).await.unwrap().unwrap();
+            ret.push((message.offset, message.payload));
+        }
+        ret
+    }
+
+    async fn nothing_to_recv(consumer: &DiskLogConsumer) -> bool {
+        tokio::time::timeout(POLL_INTERVAL * 5, consumer.recv()).await.is_err()
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let dir = temp_dir("frame");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("frames");
+        let frames = [
+            Frame { timestamp_ms: 1_700_000_000_000, headers: headers("capnp/1"), payload: b"abc".to_vec() },
+            Frame { timestamp_ms: 0, headers: Vec::new(), payload: Vec::new() },
+        ];
+        let mut bytes: Vec<u8> = frames.iter().flat_map(|f| f.encode().unwrap()).collect();
+        // half of a third message
+        bytes.extend(&frames[0].encode().unwrap()[..9]);
+        fs::write(&path, &bytes).unwrap();
+
+        let mut file = File::open(&path).unwrap();
+        assert_eq!(read_message(&mut file).unwrap().as_ref(), Some(&frames[0]));
+        assert_eq!(read_message(&mut file).unwrap().as_ref(), Some(&frames[1]));
+        let end = file.stream_position().unwrap();
+        assert_eq!(read_message(&mut file).unwrap(), None);
+        assert_eq!(file.stream_position().unwrap(), end);
+
+        // a length too short for the timestamp and headers length
+        fs::write(&path, [0, 0, 0, 3, 1, 2, 3]).unwrap();
+        assert!(read_message(&mut File::open(&path).unwrap()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn send_and_recv() {
+        let dir = temp_dir("send");
+        let producer = DiskLogProducer::new(&dir, 1 << 20, Duration::from_secs(3600)).unwrap();
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+        assert_eq!(producer.send("t", &headers("json/1"), b"a").await.unwrap(), (0, 0));
+        assert_eq!(producer.send("t", &[], b"b").await.unwrap(), (0, 1));
+
+        let consumer = DiskLogConsumer::new(&dir, "g", &["t"]).unwrap();
+        assert!(consumer.take_assigned("t", 0));
+        assert!(!consumer.take_assigned("t", 0));
+        let message = consumer.recv().await.unwrap();
+        assert_eq!((message.offset, message.payload.as_slice()), (0, &b"a"[..]));
+        assert_eq!(message.header("kafka-buffer-encoding"), Some("json/1"));
+        assert!(message.timestamp_ms.unwrap() >= before);
+        assert_eq!(recv_payloads(&consumer, 1).await, vec![(1, b"b".to_vec())]);
+        assert!(nothing_to_recv(&consumer).await);
+
+        // written while the consumer waits
+        producer.send("t", &[], b"c").await.unwrap();
+        assert_eq!(recv_payloads(&consumer, 1).await, vec![(2, b"c".to_vec())]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn truncates_half_written_tail() {
+        let dir = temp_dir("truncate");
+        let producer = DiskLogProducer::new(&dir, 1 << 20, Duration::from_secs(3600)).unwrap();
+        producer.send("t", &[], b"a").await.unwrap();
+        producer.send("t", &[], b"b").await.unwrap();
+        drop(producer);
+        let path = segment_path(&dir, "t", 0);
+        let whole = fs::metadata(&path).unwrap().len();
+        let mut file = File::options().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 100, 0, 0]).unwrap();
+
+        let producer = DiskLogProducer::new(&dir, 1 << 20, Duration::from_secs(3600)).unwrap();
+        assert_eq!(producer.send("t", &[], b"c").await.unwrap(), (0, 2));
+        let frame_len = fs::metadata(&path).unwrap().len() - whole;
+        assert_eq!(frame_len, Frame { timestamp_ms: 0, headers: Vec::new(), payload: b"c".to_vec() }.encode().unwrap().len() as u64);
+
+        let consumer = DiskLogConsumer::new(&dir, "g", &["t"]).unwrap();
+        assert_eq!(
+            recv_payloads(&consumer, 3).await,
+            vec![(0, b"a".to_vec()), (1, b"b".to_vec()), (2, b"c".to_vec())]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rotation() {
+        let dir = temp_dir("rotation");
+        // a new segment for each message
+        let producer = DiskLogProducer::new(&dir, 1, Duration::from_secs(3600)).unwrap();
+        let consumer = DiskLogConsumer::new(&dir, "g", &["t"]).unwrap();
+        assert!(nothing_to_recv(&consumer).await);
+        for payload in [b"a", b"b", b"c"] {
+            producer.send("t", &[], payload).await.unwrap();
+        }
+        assert_eq!(segment_bases(&dir, "t").unwrap(), vec![0, 1, 2]);
+        // the consumer follows into each next segment
+        assert_eq!(
+            recv_payloads(&consumer, 3).await,
+            vec![(0, b"a".to_vec()), (1, b"b".to_vec()), (2, b"c".to_vec())]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn retention() {
+        let dir = temp_dir("retention");
+        let producer = DiskLogProducer::new(&dir, 1, Duration::ZERO).unwrap();
+        producer.send("t", &[], b"a").await.unwrap();
+        producer.send("t", &[], b"b").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        // starting segment 2 removes 0 and 1, last written before now
+        producer.send("t", &[], b"c").await.unwrap();
+        assert_eq!(segment_bases(&dir, "t").unwrap(), vec![2]);
+
+        let consumer = DiskLogConsumer::new(&dir, "g", &["t"]).unwrap();
+        assert_eq!(recv_payloads(&consumer, 1).await, vec![(2, b"c".to_vec())]);
+        // a removed offset reads from the first one kept
+        consumer.seek("t", 0, 1).await.unwrap();
+        assert_eq!(recv_payloads(&consumer, 1).await, vec![(2, b"c".to_vec())]);
+        assert!(consumer.seek("u", 0, 0).await.is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn commit_and_restart() {
+        let dir = temp_dir("commit");
+        let producer = DiskLogProducer::new(&dir, 1 << 20, Duration::from_secs(3600)).unwrap();
+        for payload in [b"a", b"b", b"c"] {
+            producer.send("t", &[], payload).await.unwrap();
+        }
+        let consumer = DiskLogConsumer::new(&dir, "g", &["t"]).unwrap();
+        recv_payloads(&consumer, 2).await;
+        consumer.commit(&[("t".to_owned(), 0, 2)]).await.unwrap();
+        assert_eq!(fs::read_to_string(dir.join("t").join("offsets.g")).unwrap(), "2");
+        assert!(!dir.join("t").join("offsets.g.tmp").exists());
+        drop(consumer);
+
+        let consumer = DiskLogConsumer::new(&dir, "g", &["t"]).unwrap();
+        assert_eq!(recv_payloads(&consumer, 1).await, vec![(2, b"c".to_vec())]);
+        // other groups start from the beginning
+        let other = DiskLogConsumer::new(&dir, "h", &["t"]).unwrap();
+        assert_eq!(recv_payloads(&other, 1).await, vec![(0, b"a".to_vec())]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,258 @@
+//! How a BufferedRequest is written to the buffer.  Each route picks an
+//! encoding, and each message carries it in the `kafka-buffer-encoding`
+//! header, like `capnp-packed/1`.  Messages without the header are capnp,
+//! as everything was before the header existed.
+
+use crate::avro;
+use crate::compression::BodyCompression;
+use crate::headers::HeaderCapture;
+use crate::{decode_packed_request, decode_request, encode_compressed_request, insert_header, DecodedRequest};
+use anyhow::anyhow;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::{json, Map, Value};
+
+pub const ENCODING_HEADER: &str = "kafka-buffer-encoding";
+
+/// bumped when an encoding changes incompatibly
+const VERSION: &str = "1";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    /// buffered_http_request.capnp, unpacked
+    Capnp,
+    /// the same, with capnp's packing, smaller for mostly-empty messages
+    CapnpPacked,
+    /// {"body": ..., "headers": {...}}, with body_base64 instead of body if
+    /// the body isn't UTF-8
+    Json,
+    /// buffered_http_request.proto
+    Protobuf,
+    /// Confluent framing, with the id of the schema registered for the
+    /// route's topic, or 0 until the producer registers it
+    Avro { schema_id: u32 },
+}
+
+/// the protobuf equivalent of BufferedRequest
+#[derive(Clone, PartialEq, prost::Message)]
+struct ProtoRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    body: Vec<u8>,
+    #[prost(message, repeated, tag = "2")]
+    headers: Vec<ProtoHeader>,
+    #[prost(string, optional, tag = "3")]
+    body_ref: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ProtoHeader {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(bytes = "vec", tag = "2")]
+    value: Vec<u8>,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Capnp => "capnp",
+            Encoding::CapnpPacked => "capnp-packed",
+            Encoding::Json => "json",
+            Encoding::Protobuf => "protobuf",
+            Encoding::Avro { .. } => "avro",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "capnp" => Some(Encoding::Capnp),
+            "capnp-packed" => Some(Encoding::CapnpPacked),
+            "json" => Some(Encoding::Json),
+            "protobuf" => Some(Encoding::Protobuf),
+            "avro" => Some(Encoding::Avro { schema_id: 0 }),
+            _ => None,
+        }
+    }
+
+    /// the value of the encoding header
+    pub fn header_value(&self) -> String {
+        format!("{}/{}", self.name(), VERSION)
+    }
+
+    /// the encoding named by a message's header, capnp if there is none
+    pub fn from_header(o_value: Option<&str>) -> anyhow::Result<Encoding> {
+        let Some(value) = o_value else {
+            return Ok(Encoding::Capnp);
+        };
+        let (name, version) = value.split_once('/').unwrap_or((value, VERSION));
+        let encoding = Encoding::from_name(name).ok_or(anyhow!("unknown encoding {}", value))?;
+        if version != VERSION {
+            return Err(anyhow!("unsupported {} version {}", name, version));
+        }
+        Ok(encoding)
+    }
+
+    /// The request as the producer received it, with the route's headers.
+    /// Only the capnp encodings can compress the body.
+    pub fn encode_request(
+        &self,
+        body: &[u8],
+        headers: &HeaderMap,
+        capture: &HeaderCapture,
+        compression: Option<&BodyCompression>,
+    ) -> Vec<u8> {
+        self.encode_captured(body, &capture.select(headers), compression)
+    }
+
+    /// encode_request with headers already captured, and maybe rewritten
+    pub fn encode_captured(
+        &self,
+        body: &[u8],
+        values: &[(&HeaderName, &HeaderValue)],
+        compression: Option<&BodyCompression>,
+    ) -> Vec<u8> {
+        if *self == Encoding::Capnp {
+            return encode_compressed_request(body, values, compression);
+        }
+        self.encode(&DecodedRequest::from_http(body, values), compression)
+    }
+
+    pub fn encode(&self, request: &DecodedRequest, compression: Option<&BodyCompression>) -> Vec<u8> {
+        match self {
+            Encoding::Capnp => request.encode_compressed(compression),
+            Encoding::CapnpPacked => request.encode_packed(compression),
+            Encoding::Json => {
+                let mut json = json!({"headers": request.headers});
+                match std::str::from_utf8(&request.body) {
+                    Ok(body) => json["body"] = json!(body),
+                    Err(_) => json["body_base64"] = json!(STANDARD.encode(&request.body)),
+                }
+                if let Some(body_ref) = &request.body_ref {
+                    json["body_ref"] = json!(body_ref);
+                }
+                json.to_string().into_bytes()
+            }
+            Encoding::Protobuf => prost::Message::encode_to_vec(&ProtoRequest {
+                body: request.body.clone(),
+                headers: request
+                    .header_values()
+                    .map(|(name, value)| ProtoHeader {
+                        name: name.to_owned(),
+                        value: value.as_bytes().to_vec(),
+                    })
+                    .collect(),
+                body_ref: request.body_ref.clone(),
+            }),
+            Encoding::Avro { schema_id } => avro::encode(*schema_id, request),
+        }
+    }
+
+    /// Avro is decoded by SchemaRegistry::decode, which knows the schema
+    /// each message was written with.
+    pub fn decode(&self, bytes: &[u8]) -> anyhow::Result<DecodedRequest> {
+        match self {
+            Encoding::Capnp => decode_request(Some(bytes)),
+            Encoding::CapnpPacked => decode_packed_request(bytes),
+            Encoding::Json => {
+                let json: Value = serde_json::from_slice(bytes)?;
+                let body_ref = json.get("body_ref").and_then(Value::as_str).map(str::to_owned);
+                let body = match (json.get("body").and_then(Value::as_str), json.get("body_base64").and_then(Value::as_str)) {
+                    (Some(body), _) => body.as_bytes().to_vec(),
+                    (None, Some(body)) => STANDARD.decode(body)?,
+                    (None, None) if body_ref.is_some() => Vec::new(),
+                    (None, None) => return Err(anyhow!("json request has no body")),
+                };
+                let headers = match json.get("headers") {
+                    Some(Value::Object(headers)) => headers.clone(),
+                    _ => Map::new(),
+                };
+                Ok(DecodedRequest { body, headers, body_ref })
+            }
+            Encoding::Protobuf => {
+                let proto: ProtoRequest = prost::Message::decode(bytes)?;
+                let mut headers = Map::new();
+                for h in proto.headers {
+                    insert_header(&mut headers, h.name, String::from_utf8(h.value)?);
+                }
+                Ok(DecodedRequest {
+                    body: proto.body,
+                    headers,
+                    body_ref: proto.body_ref,
+                })
+            }
+            Encoding::Avro { .. } => Err(anyhow!("avro needs a schema registry to decode")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Codec;
+
+    const ENCODINGS: [Encoding; 4] = [Encoding::Capnp, Encoding::CapnpPacked, Encoding::Json, Encoding::Protobuf];
+
+    fn values() -> Vec<(HeaderName, HeaderValue)> {
+        vec![
+            (HeaderName::from_static("content-type"), HeaderValue::from_static("application/json")),
+            (HeaderName::from_static("x-event"), HeaderValue::from_static("created")),
+            (HeaderName::from_static("x-event"), HeaderValue::from_static("updated")),
+        ]
+    }
+
+    fn round_trip(encoding: Encoding, body: &[u8], compression: Option<&BodyCompression>) -> DecodedRequest {
+        let values = values();
+        let values: Vec<_> = values.iter().map(|(name, value)| (name, value)).collect();
+        let bytes = encoding.encode_captured(body, &values, compression);
+        encoding.decode(&bytes).unwrap()
+    }
+
+    #[test]
+    fn round_trips() {
+        let headers = json!({"content-type": "application/json", "x-event": ["created", "updated"]});
+        // not UTF-8, which json encodes as base64
+        let binary = [0xff, 0x00, 0xfe];
+        for encoding in ENCODINGS {
+            for body in [&b"{\"id\": 1}"[..], &binary[..], &b""[..]] {
+                let request = round_trip(encoding, body, None);
+                assert_eq!(request.body, body, "{}", encoding.name());
+                assert_eq!(Value::Object(request.headers), headers, "{}", encoding.name());
+                assert_eq!(request.body_ref, None, "{}", encoding.name());
+            }
+        }
+    }
+
+    #[test]
+    fn compressed_round_trips() {
+        let body = "{\"id\": 1}".repeat(100);
+        for encoding in [Encoding::Capnp, Encoding::CapnpPacked] {
+            for codec in [Codec::Zstd, Codec::Gzip] {
+                let compression = BodyCompression { codec, above: 10 };
+                assert_eq!(round_trip(encoding, body.as_bytes(), Some(&compression)).body, body.as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn body_ref_round_trips() {
+        let mut request = DecodedRequest::from_http(&[], &[]);
+        request.body_ref = Some("s3://bodies/t/1".to_string());
+        for encoding in ENCODINGS {
+            let decoded = encoding.decode(&encoding.encode(&request, None)).unwrap();
+            assert_eq!(decoded.body_ref, request.body_ref, "{}", encoding.name());
+            assert!(decoded.body.is_empty());
+        }
+    }
+
+    #[test]
+    fn header() {
+        for encoding in ENCODINGS {
+            assert_eq!(Encoding::from_header(Some(&encoding.header_value())).unwrap(), encoding);
+        }
+        assert_eq!(Encoding::from_header(None).unwrap(), Encoding::Capnp);
+        assert_eq!(Encoding::from_header(Some("json")).unwrap(), Encoding::Json);
+        assert!(Encoding::from_header(Some("json/2")).is_err());
+        assert!(Encoding::from_header(Some("thrift/1")).is_err());
+    }
+}
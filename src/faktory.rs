@@ -0,0 +1,273 @@
+//! Faktory jobs, pushed over the Faktory work protocol.
+//!
+//! https://github.com/contribsys/faktory/blob/main/docs/protocol-specification.md
+
+use crate::body::lossless_string;
+use crate::config::Route;
+use crate::jid;
+use crate::job::run_at;
+use crate::observability::hist_time_since;
+use crate::sink::{JobSink, Outcome, Record};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use lazy_static::lazy_static;
+use prometheus::{register_histogram, Histogram};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::time::Instant;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tracing::*;
+use url::Url;
+
+lazy_static! {
+    static ref FAKTORY_DURATION_S: Histogram =
+        register_histogram!("faktory_duration_s", "duration of pushes to Faktory").unwrap();
+}
+
+type Connection = BufReader<TcpStream>;
+
+pub struct Faktory {
+    /// like tcp://:password@localhost:7419
+    url: Url,
+    /// opened on first use, and again after any error
+    conn: Mutex<Option<Connection>>,
+}
+
+impl Faktory {
+    pub fn new(url: Url) -> Faktory {
+        Faktory {
+            url,
+            conn: Mutex::new(None),
+        }
+    }
+
+    async fn push_job(&self, job: &Value) -> anyhow::Result<()> {
+        let mut guard = self.conn.lock().await;
+        if guard.is_none() {
+            *guard = Some(connect(&self.url).await?);
+        }
+        let conn = guard.as_mut().expect("connected above");
+        let r_push = match command(conn, "PUSH", Some(job)).await {
+            Ok(reply) if reply == "OK" => Ok(()),
+            Ok(reply) => Err(anyhow!("Faktory PUSH: {}", reply)),
+            Err(err) => Err(err),
+        };
+        if r_push.is_err() {
+            // the connection may be in the middle of a reply, start over
+            *guard = None;
+        }
+        r_push
+    }
+}
+
+async fn connect(url: &Url) -> anyhow::Result<Connection> {
+    let host = url.host_str().unwrap_or("localhost");
+    let port = url.port().unwrap_or(7419);
+    let mut conn = BufReader::new(TcpStream::connect((host, port)).await?);
+    let hi = read_reply(&mut conn).await?;
+    let hi: Value = serde_json::from_str(
+        hi.strip_prefix("HI ")
+            .ok_or(anyhow!("expected HI from Faktory, got {}", hi))?,
+    )?;
+    let mut hello = json!({
+        "v": 2,
+        "hostname": std::env::var("HOSTNAME").unwrap_or("kafka-buffer".to_string()),
+        "pid": std::process::id(),
+    });
+    if let Some(salt) = hi.get("s").and_then(Value::as_str) {
+        let password = url
+            .password()
+            .ok_or(anyhow!("Faktory requires a password, set one in FAKTORY_URL"))?;
+        let iterations = hi.get("i").and_then(Value::as_u64).unwrap_or(1);
+        hello["pwdhash"] = json!(password_hash(password, salt, iterations));
+    }
+    match command(&mut conn, "HELLO", Some(&hello)).await? {
+        reply if reply == "OK" => Ok(conn),
+        reply => Err(anyhow!("Faktory HELLO: {}", reply)),
+    }
+}
+
+/// SHA256 of password and salt, then of the hash, `iterations` times in all
+fn password_hash(password: &str, salt: &str, iterations: u64) -> String {
+    let mut hash = Sha256::digest(format!("{}{}", password, salt).as_bytes());
+    for _ in 1..iterations {
+        hash = Sha256::digest(hash);
+    }
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+async fn command(conn: &mut Connection, verb: &str, o_payload: Option<&Value>) -> anyhow::Result<String> {
+    let line = match o_payload {
+        None => format!("{}\r\n", verb),
+        Some(payload) => format!("{} {}\r\n", verb, payload),
+    };
+    conn.write_all(line.as_bytes()).await?;
+    conn.flush().await?;
+    read_reply(conn).await
+}
+
+/// A simple or bulk string reply.  Error replies are returned as strings,
+/// so the caller can say which command failed.
+async fn read_reply(conn: &mut Connection) -> anyhow::Result<String> {
+    let mut line = String::new();
+    if conn.read_line(&mut line).await? == 0 {
+        return Err(anyhow!("Faktory closed the connection"));
+    }
+    let line = line.trim_end_matches(['\r', '\n']);
+    match line.as_bytes().first() {
+        Some(b'+') | Some(b'-') => Ok(line[1..].to_owned()),
+        Some(b'$') => {
+            let len: i64 = line[1..].parse()?;
+            if len < 0 {
+                return Ok(String::new());
+            }
+            let mut buf = vec![0; len as usize + 2]; // and \r\n
+            conn.read_exact(&mut buf).await?;
+            buf.truncate(len as usize);
+            Ok(String::from_utf8(buf)?)
+        }
+        _ => Err(anyhow!("unexpected reply from Faktory: {}", line)),
+    }
+}
+
+fn iso8601(epoch_s: f64) -> Option<String> {
+    let at = DateTime::<Utc>::from_timestamp(epoch_s.trunc() as i64, (epoch_s.fract() * 1e9) as u32)?;
+    Some(at.to_rfc3339_opts(SecondsFormat::Micros, true))
+}
+
+/// Faktory's retry count, from the route's Sidekiq-style retry option
+fn retry(route: &Route) -> Option<i64> {
+    match route.job_fields.get("retry") {
+        Some(Value::Bool(false)) => Some(0),
+        Some(Value::Number(n)) => n.as_i64(),
+        _ => None,
+    }
+}
+
+#[async_trait]
+impl JobSink for Faktory {
+    async fn push(&self, route: &Route, record: &Record) -> anyhow::Result<Outcome> {
+        let jid = jid::for_record(route, record);
+        let mut job = json!({
+            "jid": jid,
+            "jobtype": route.job_class,
+            "queue": route.queue,
+        });
+        if let Some(retry) = retry(route) {
+            job["retry"] = json!(retry);
+        }
+        let outcome = match record.body_args(route) {
+            Ok(mut args) => {
+                args.push(Value::Object(record.request.headers.clone()));
+                job["args"] = Value::Array(args);
+                match run_at(route, record).and_then(iso8601) {
+                    None => Outcome::Enqueued,
+                    Some(at) => {
+                        job["at"] = json!(at);
+                        Outcome::Scheduled
+                    }
+                }
+            }
+            Err(reason) => {
+                // Faktory can't push to its dead set, so use a queue no one works
+                let dead_queue = format!("{}-dead", route.queue);
+                warn!("writing jid={} to queue {}: {}", jid, dead_queue, reason);
+                job["args"] = json!([
+                    lossless_string(&record.request.body),
+                    record.request.headers,
+                ]);
+                job["queue"] = json!(dead_queue);
+                job["custom"] = json!({"kafka_buffer_error": reason});
+                Outcome::DeadLettered
+            }
+        };
+        let start = Instant::now();
+        let r_push = self.push_job(&job).await;
+        hist_time_since(&FAKTORY_DURATION_S, start);
+        r_push?;
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Faktory's side of one connection: HI with a salt, OK to HELLO and
+    /// the first PUSH, and ERR to the second.  Returns each command.
+    async fn stand_in(listener: TcpListener) -> Vec<(String, Value)> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut conn = BufReader::new(stream);
+        conn.write_all(b"+HI {\"v\":2,\"s\":\"123456789abc\",\"i\":3}\r\n").await.unwrap();
+        let mut commands = Vec::new();
+        for reply in ["+OK\r\n", "+OK\r\n", "-ERR Invalid job\r\n"] {
+            let mut line = String::new();
+            conn.read_line(&mut line).await.unwrap();
+            let (verb, payload) = line.trim_end().split_once(' ').unwrap();
+            commands.push((verb.to_owned(), serde_json::from_str(payload).unwrap()));
+            conn.write_all(reply.as_bytes()).await.unwrap();
+        }
+        commands
+    }
+
+    #[tokio::test]
+    async fn handshake_push_and_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let faktory = Faktory::new(format!("tcp://:secret@{}", listener.local_addr().unwrap()).parse().unwrap());
+        let server = tokio::spawn(stand_in(listener));
+
+        faktory.push_job(&json!({"jid": "a", "jobtype": "T", "args": []})).await.unwrap();
+        let err = faktory.push_job(&json!({"jid": "b", "jobtype": "T", "args": []})).await.unwrap_err();
+        assert!(err.to_string().contains("ERR Invalid job"), "{}", err);
+        // and connects again for the next push
+        assert!(faktory.conn.lock().await.is_none());
+
+        let commands = server.await.unwrap();
+        let (verb, hello) = &commands[0];
+        assert_eq!(verb, "HELLO");
+        assert_eq!(hello["v"], 2);
+        // SHA256 of the password and salt, and twice more of the hash
+        let mut hash = Sha256::digest(b"secret123456789abc");
+        for _ in 0..2 {
+            hash = Sha256::digest(hash);
+        }
+        let expected: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(hello["pwdhash"], json!(expected));
+        assert_eq!(commands[1].0, "PUSH");
+        assert_eq!(commands[1].1["jid"], "a");
+        assert_eq!(commands[2].0, "PUSH");
+        assert_eq!(commands[2].1["jid"], "b");
+    }
+
+    #[tokio::test]
+    async fn password_required() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url: Url = format!("tcp://{}", listener.local_addr().unwrap()).parse().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"+HI {\"v\":2,\"s\":\"123456789abc\",\"i\":3}\r\n").await.unwrap();
+            // until the client hangs up
+            let _ = stream.read_u8().await;
+        });
+        let err = connect(&url).await.unwrap_err();
+        assert!(err.to_string().contains("requires a password"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn bulk_replies() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"$5\r\nhello\r\n$-1\r\n*1\r\n").await.unwrap();
+        });
+        let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        assert_eq!(read_reply(&mut conn).await.unwrap(), "hello");
+        assert_eq!(read_reply(&mut conn).await.unwrap(), "");
+        assert!(read_reply(&mut conn).await.is_err());
+    }
+}
@@ -0,0 +1,323 @@
+//! Which request headers a route passes through the buffer: exact names,
+//! globs like `x-shopify-*`, or every header except a denylist.  Routes can
+//! rename the headers they capture, and set others from templates.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use std::collections::HashMap;
+use tracing::*;
+
+/// never captured by globs or all-except, only by exact name
+pub const DEFAULT_DENY: [&str; 4] = ["authorization", "proxy-authorization", "cookie", "set-cookie"];
+
+/// A header name pattern, where `*` matches any characters and `?` any one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Glob(String);
+
+impl Glob {
+    pub fn parse(s: &str) -> Result<Glob, String> {
+        let pattern = s.to_ascii_lowercase();
+        // the pattern, less its wildcards, must be part of a valid header name
+        let literal: String = pattern.chars().filter(|c| *c != '*' && *c != '?').collect();
+        if !literal.is_empty() && HeaderName::from_bytes(literal.as_bytes()).is_err() {
+            return Err(format!("invalid header pattern {}", s));
+        }
+        if pattern.is_empty() {
+            return Err("empty header pattern".to_string());
+        }
+        Ok(Glob(pattern))
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        let (pattern, name) = (self.0.as_bytes(), name.as_bytes());
+        let (mut p, mut n) = (0, 0);
+        // where to resume after the last *, trying one more character each time
+        let mut star: Option<(usize, usize)> = None;
+        while n < name.len() {
+            match pattern.get(p) {
+                Some(b'*') => {
+                    star = Some((p, n));
+                    p += 1;
+                }
+                Some(c) if *c == b'?' || *c == name[n] => {
+                    p += 1;
+                    n += 1;
+                }
+                _ => match star {
+                    Some((sp, sn)) => {
+                        p = sp + 1;
+                        n = sn + 1;
+                        star = Some((sp, sn + 1));
+                    }
+                    None => return false,
+                },
+            }
+        }
+        pattern[p..].iter().all(|c| *c == b'*')
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct HeaderCapture {
+    /// captured by exact name, even if denied
+    pub names: Vec<HeaderName>,
+    pub globs: Vec<Glob>,
+    /// capture every header except these and DEFAULT_DENY
+    pub all_except: Option<Vec<Glob>>,
+}
+
+impl HeaderCapture {
+    pub fn names(names: Vec<HeaderName>) -> HeaderCapture {
+        HeaderCapture {
+            names,
+            ..HeaderCapture::default()
+        }
+    }
+
+    pub fn captures(&self, name: &HeaderName) -> bool {
+        if self.names.contains(name) {
+            return true;
+        }
+        if DEFAULT_DENY.contains(&name.as_str()) {
+            return false;
+        }
+        match &self.all_except {
+            Some(denied) => !denied.iter().any(|g| g.matches(name.as_str())),
+            None => self.globs.iter().any(|g| g.matches(name.as_str())),
+        }
+    }
+
+    /// Each value of each captured header, exact names first in the order
+    /// they're listed, then the rest in the order they arrived.
+    pub fn select<'a>(&'a self, headers: &'a HeaderMap) -> Vec<(&'a HeaderName, &'a HeaderValue)> {
+        let mut values: Vec<_> = self
+            .names
+            .iter()
+            .flat_map(|name| headers.get_all(name).iter().map(move |value| (name, value)))
+            .collect();
+        if !self.globs.is_empty() || self.all_except.is_some() {
+            for name in headers.keys() {
+                if !self.names.contains(name) && self.captures(name) {
+                    values.extend(headers.get_all(name).iter().map(|value| (name, value)));
+                }
+            }
+        }
+        values
+    }
+}
+
+/// variables every template can use, besides the route's path captures
+pub const TEMPLATE_VARS: [&str; 3] = ["path", "received_at", "received_at_ms"];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Var(String),
+}
+
+/// A header value like `shopify-{provider}`, filled in for each request.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template(Vec<Part>);
+
+impl Template {
+    /// `captures` are the names of the route's path captures
+    pub fn parse(s: &str, captures: &[String]) -> Result<Template, String> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            let end = start + rest[start..].find('}').ok_or_else(|| format!("unclosed {{ in {}", s))?;
+            let var = &rest[start + 1..end];
+            if !TEMPLATE_VARS.contains(&var) && !captures.iter().any(|c| c == var) {
+                return Err(format!("unknown variable {{{}}} in {}", var, s));
+            }
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_owned()));
+            }
+            parts.push(Part::Var(var.to_owned()));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_owned()));
+        }
+        let literal: String = parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::Literal(s) => Some(s.as_str()),
+                Part::Var(_) => None,
+            })
+            .collect();
+        if HeaderValue::from_str(&literal).is_err() {
+            return Err(format!("invalid header value {}", s));
+        }
+        Ok(Template(parts))
+    }
+
+    pub fn render(&self, vars: &TemplateVars) -> String {
+        self.0
+            .iter()
+            .map(|part| match part {
+                Part::Literal(s) => s.clone(),
+                Part::Var(var) => vars.get(var),
+            })
+            .collect()
+    }
+}
+
+/// what templates are filled in with, for one request
+pub struct TemplateVars<'a> {
+    pub path: &'a str,
+    /// path capture name => the segment of the path it matched
+    pub captures: &'a HashMap<String, String>,
+    pub received_at: DateTime<Utc>,
+}
+
+impl TemplateVars<'_> {
+    fn get(&self, var: &str) -> String {
+        match var {
+            "path" => self.path.to_owned(),
+            "received_at" => self.received_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "received_at_ms" => self.received_at.timestamp_millis().to_string(),
+            capture => self.captures.get(capture).cloned().unwrap_or_default(),
+        }
+    }
+}
+
+/// changes to the captured headers, before they're buffered
+#[derive(Clone, Debug, Default)]
+pub struct HeaderRewrite {
+    /// captured name => the name it's buffered as
+    pub rename: Vec<(HeaderName, HeaderName)>,
+    /// replaces any captured values of the same header
+    pub set: Vec<(HeaderName, Template)>,
+}
+
+impl HeaderRewrite {
+    pub fn is_empty(&self) -> bool {
+        self.rename.is_empty() && self.set.is_empty()
+    }
+
+    /// the captured values, renamed, and then the values set
+    pub fn apply(&self, values: &[(&HeaderName, &HeaderValue)], vars: &TemplateVars) -> Vec<(HeaderName, HeaderValue)> {
+        let mut ret: Vec<(HeaderName, HeaderValue)> = values
+            .iter()
+            .map(|(name, value)| match self.rename.iter().find(|(from, _)| from == *name) {
+                Some((_, to)) => (to.clone(), (*value).clone()),
+                None => ((*name).clone(), (*value).clone()),
+            })
+            .filter(|(name, _)| !self.set.iter().any(|(n, _)| n == name))
+            .collect();
+        for (name, template) in &self.set {
+            let rendered = template.render(vars);
+            match HeaderValue::from_str(&rendered) {
+                Ok(value) => ret.push((name.clone(), value)),
+                Err(_) => warn!("not setting header {}, invalid value {:?}", name, rendered),
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(s: &str) -> Glob {
+        Glob::parse(s).unwrap()
+    }
+
+    #[test]
+    fn glob_matches() {
+        assert!(glob("x-shopify-*").matches("x-shopify-topic"));
+        assert!(glob("x-shopify-*").matches("x-shopify-"));
+        assert!(!glob("x-shopify-*").matches("x-shopif"));
+        assert!(glob("X-Hub-*").matches("x-hub-signature"));
+        assert!(glob("x-?-id").matches("x-a-id"));
+        assert!(!glob("x-?-id").matches("x-ab-id"));
+        // the * has to give back characters to match what follows it
+        assert!(glob("x-*-id").matches("x-request-id-id"));
+        assert!(glob("*id*").matches("x-request-id"));
+        assert!(!glob("*-id").matches("x-request-ids"));
+        assert!(glob("*").matches("anything"));
+        assert!(glob("content-type").matches("content-type"));
+        assert!(!glob("content-type").matches("content-types"));
+    }
+
+    #[test]
+    fn glob_parse() {
+        assert_eq!(Glob::parse(""), Err("empty header pattern".to_string()));
+        assert_eq!(Glob::parse("x shopify *"), Err("invalid header pattern x shopify *".to_string()));
+        assert!(Glob::parse("**").is_ok());
+    }
+
+    #[test]
+    fn capture() {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [("content-type", "a"), ("authorization", "b"), ("x-hub-one", "c"), ("x-forwarded-for", "d")] {
+            headers.append(HeaderName::from_static(name), HeaderValue::from_static(value));
+        }
+        let selected = |capture: &HeaderCapture| -> Vec<String> {
+            capture.select(&headers).iter().map(|(name, _)| name.to_string()).collect()
+        };
+        let globs = HeaderCapture {
+            names: vec![HeaderName::from_static("content-type")],
+            globs: vec![glob("x-hub-*"), glob("*")],
+            all_except: None,
+        };
+        // authorization only by exact name
+        assert_eq!(selected(&globs), ["content-type", "x-hub-one", "x-forwarded-for"]);
+        let all_except = HeaderCapture {
+            all_except: Some(vec![glob("x-forwarded-*")]),
+            ..HeaderCapture::names(vec![HeaderName::from_static("authorization")])
+        };
+        assert_eq!(selected(&all_except), ["authorization", "content-type", "x-hub-one"]);
+    }
+
+    fn vars(captures: &HashMap<String, String>) -> TemplateVars {
+        TemplateVars {
+            path: "/hooks/shopify",
+            captures,
+            received_at: DateTime::<Utc>::from_timestamp(1_700_000_000, 250_000_000).unwrap(),
+        }
+    }
+
+    #[test]
+    fn template() {
+        let captures = HashMap::from([("provider".to_string(), "shopify".to_string())]);
+        let names = ["provider".to_string()];
+        let render = |s: &str| Template::parse(s, &names).unwrap().render(&vars(&captures));
+        assert_eq!(render("from-{provider}"), "from-shopify");
+        assert_eq!(render("{path} at {received_at}"), "/hooks/shopify at 2023-11-14T22:13:20.250Z");
+        assert_eq!(render("{received_at_ms}"), "1700000000250");
+        assert_eq!(render("production"), "production");
+        assert_eq!(render(""), "");
+    }
+
+    #[test]
+    fn template_parse() {
+        let names = ["provider".to_string()];
+        assert_eq!(Template::parse("{tenant}", &names), Err("unknown variable {tenant} in {tenant}".to_string()));
+        assert_eq!(Template::parse("x-{provider", &names), Err("unclosed { in x-{provider".to_string()));
+        assert_eq!(Template::parse("a\nb{path}", &names), Err("invalid header value a\nb{path}".to_string()));
+    }
+
+    #[test]
+    fn rewrite() {
+        let rewrite = HeaderRewrite {
+            rename: vec![(HeaderName::from_static("x-shopify-topic"), HeaderName::from_static("x-event-type"))],
+            set: vec![
+                (HeaderName::from_static("x-source"), Template::parse("{provider}", &["provider".to_string()]).unwrap()),
+                (HeaderName::from_static("content-type"), Template::parse("application/json", &[]).unwrap()),
+            ],
+        };
+        let (topic, content_type) = (HeaderName::from_static("x-shopify-topic"), HeaderName::from_static("content-type"));
+        let (created, text) = (HeaderValue::from_static("orders/create"), HeaderValue::from_static("text/plain"));
+        let captures = HashMap::from([("provider".to_string(), "shopify".to_string())]);
+        let rewritten: Vec<(String, String)> = rewrite
+            .apply(&[(&topic, &created), (&content_type, &text)], &vars(&captures))
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_string()))
+            .collect();
+        let expected = [("x-event-type", "orders/create"), ("x-source", "shopify"), ("content-type", "application/json")];
+        assert_eq!(rewritten, expected.map(|(n, v)| (n.to_string(), v.to_string())));
+    }
+}
@@ -0,0 +1,89 @@
+//! Deterministic Sidekiq jids, so a replayed Kafka message becomes the same
+//! job, and a job can be traced back to the record it came from.
+
+use crate::config::Route;
+use crate::job::Destination;
+use crate::sink::Record;
+use redis::aio::MultiplexedConnection;
+use redis::Script;
+
+// KEYS: jid key, queue list, set of queue names, sorted set (schedule or dead)
+// ARGV: window s, queue name, job json, score (empty to enqueue now)
+// returns 0 if the jid was written within the window, otherwise 1
+const WRITE_ONCE: &str = r#"
+if not redis.call('SET', KEYS[1], 1, 'NX', 'EX', ARGV[1]) then
+    return 0
+end
+if ARGV[4] == '' then
+    redis.call('SADD', KEYS[3], ARGV[2])
+    redis.call('LPUSH', KEYS[2], ARGV[3])
+else
+    redis.call('ZADD', KEYS[4], ARGV[4], ARGV[3])
+end
+return 1
+"#;
+
+/// from the route's request id header, if the request has one, otherwise
+/// from the Kafka coordinates
+pub fn for_record(route: &Route, record: &Record) -> String {
+    route
+        .jid_header
+        .as_ref()
+        .and_then(|h| record.request.header(h))
+        .filter(|request_id| !request_id.is_empty())
+        .map(|request_id| from_request_id(&record.topic, request_id))
+        .unwrap_or_else(|| from_coordinates(&record.topic, record.partition, record.offset))
+}
+
+pub fn from_coordinates(topic: &str, partition: i32, offset: i64) -> String {
+    format!("{}-{}-{}", topic, partition, offset)
+}
+
+/// for senders which set their own request id header
+pub fn from_request_id(topic: &str, request_id: &str) -> String {
+    format!("{}-{}", topic, request_id)
+}
+
+/// Remembers jids written in the last `window_s` seconds, so that
+/// duplicates within the window can be skipped.  The jid is marked in the
+/// same script as the job is written, so two consumers can't both write
+/// it, and a crash can't mark a job which wasn't written.
+pub struct DedupeWindow {
+    conn: MultiplexedConnection,
+    pub window_s: u64,
+    script: Script,
+}
+
+impl DedupeWindow {
+    pub fn new(conn: MultiplexedConnection, window_s: u64) -> DedupeWindow {
+        DedupeWindow {
+            conn,
+            window_s,
+            script: Script::new(WRITE_ONCE),
+        }
+    }
+
+    /// Write a Sidekiq job, like Sidekiq::write, unless its jid was written
+    /// within the window.  Returns whether it was written.
+    pub async fn write(&self, queue: &str, destination: Destination, job: &str, jid: &str) -> anyhow::Result<bool> {
+        let mut conn = self.conn.clone();
+        let sorted_set = destination.sorted_set();
+        let mut invocation = self.script.prepare_invoke();
+        invocation
+            .key(key(jid))
+            .key(format!("queue:{}", queue))
+            .key("queues")
+            .key(sorted_set.map(|(key, _)| key).unwrap_or("schedule"))
+            .arg(self.window_s)
+            .arg(queue)
+            .arg(job)
+            .arg(sorted_set.map(|(_, score)| score.to_string()).unwrap_or_default());
+        let written: i64 = invocation.invoke_async(&mut conn).await?;
+        Ok(written == 1)
+    }
+}
+
+/// the key which marks a jid as written
+pub fn key(jid: &str) -> String {
+    format!("kafka-buffer:jid:{}", jid)
+}
@@ -0,0 +1,358 @@
+//! Sidekiq jobs, written directly to Redis so that routes can set any of
+//! Sidekiq's job fields.
+
+use crate::body::lossless_string;
+use crate::config::{JobFormat, Route};
+use crate::jid::{self, DedupeWindow};
+use crate::observability::hist_time_since;
+use crate::offsets::RedisOffsets;
+use crate::sink::{push_in_order, JobSink, Outcome, Record};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use chrono::{SecondsFormat, Utc};
+use lazy_static::lazy_static;
+use prometheus::{register_histogram, Histogram};
+use redis::aio::MultiplexedConnection;
+use serde_json::{json, Value};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::*;
+
+lazy_static! {
+    static ref REDIS_DURATION_S: Histogram =
+        register_histogram!("redis_duration_s", "duration of writes to Redis queues").unwrap();
+    static ref REDIS_SCHEDULE_DURATION_S: Histogram =
+        register_histogram!("redis_schedule_duration_s", "duration of writes to the Sidekiq schedule").unwrap();
+}
+
+/// job fields set by kafka-buffer, which routes may not override.  A job
+/// with "at" is put back in the schedule each time Sidekiq pushes it.
+pub const RESERVED_KEYS: [&str; 8] = ["class", "wrapped", "args", "queue", "jid", "created_at", "enqueued_at", "at"];
+
+const ACTIVE_JOB_WRAPPER: &str = "ActiveJob::QueueAdapters::SidekiqAdapter::JobWrapper";
+
+/// where a Sidekiq job is written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Destination {
+    Queue,
+    /// the schedule sorted set, to run at this time
+    Schedule(f64),
+    /// the dead set, for requests which can't become jobs
+    Dead,
+}
+
+/// The Sidekiq job for a request, or a job for the dead set, with the
+/// raw body, if the body doesn't fit the route's body mode or the record
+/// has an error.
+pub fn job_for_request(route: &Route, record: &Record, jid: &str) -> (Destination, Value) {
+    let request = &record.request;
+    match record.body_args(route) {
+        Ok(mut args) => {
+            args.push(Value::Object(request.headers.clone()));
+            let destination = match run_at(route, record) {
+                None => Destination::Queue,
+                Some(at) => Destination::Schedule(at),
+            };
+            (destination, sidekiq_job(route, args, jid, destination))
+        }
+        Err(reason) => {
+            let args = vec![
+                Value::String(lossless_string(&request.body)),
+                Value::Object(request.headers.clone()),
+            ];
+            let mut job = sidekiq_job(route, args, jid, Destination::Dead);
+            job["error_class"] = json!("KafkaBuffer::InvalidBody");
+            job["error_message"] = json!(reason);
+            job["failed_at"] = json!(epoch_s());
+            (Destination::Dead, job)
+        }
+    }
+}
+
+pub fn sidekiq_job(route: &Route, args: Vec<Value>, jid: &str, destination: Destination) -> Value {
+    let now = epoch_s();
+    let mut job = route.job_fields.clone();
+    match route.format {
+        JobFormat::Sidekiq => {
+            job.insert("class".to_owned(), Value::String(route.job_class.clone()));
+            job.insert("args".to_owned(), Value::Array(args));
+        }
+        JobFormat::ActiveJob => {
+            job.insert("class".to_owned(), Value::String(ACTIVE_JOB_WRAPPER.to_owned()));
+            job.insert("wrapped".to_owned(), Value::String(route.job_class.clone()));
+            job.insert("args".to_owned(), json!([active_job_data(route, args, jid)]));
+        }
+    }
+    job.insert("queue".to_owned(), Value::String(route.queue.clone()));
+    job.insert("jid".to_owned(), Value::String(jid.to_owned()));
+    job.insert("created_at".to_owned(), Value::from(now));
+    if destination == Destination::Queue {
+        // Sidekiq sets this when it moves scheduled jobs to the queue
+        job.insert("enqueued_at".to_owned(), Value::from(now));
+    }
+    // same default as Sidekiq
+    job.entry("retry").or_insert(Value::Bool(true));
+    Value::Object(job)
+}
+
+/// the hash from ActiveJob::Core#serialize
+fn active_job_data(route: &Route, args: Vec<Value>, jid: &str) -> Value {
+    json!({
+        "job_class": route.job_class,
+        "job_id": jid,
+        "provider_job_id": null,
+        "queue_name": route.queue,
+        "priority": null,
+        "arguments": args.into_iter().map(active_job_argument).collect::<Vec<_>>(),
+        "executions": 0,
+        "exception_executions": {},
+        "locale": "en",
+        "timezone": "UTC",
+        "enqueued_at": Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true),
+    })
+}
+
+/// ActiveJob marks each serialized Hash with its symbol keys
+fn active_job_argument(arg: Value) -> Value {
+    match arg {
+        Value::Object(mut hash) => {
+            hash.insert("_aj_symbol_keys".to_owned(), json!([]));
+            Value::Object(hash)
+        }
+        other => other,
+    }
+}
+
+/// When to run the job, in seconds since the epoch, if it should be
+/// scheduled rather than enqueued now.  A time given in the request takes
+/// precedence over the route's delay, which counts from when the request
+/// was buffered.
+pub fn run_at(route: &Route, record: &Record) -> Option<f64> {
+    let request = &record.request;
+    let requested = route
+        .run_at_header
+        .as_ref()
+        .and_then(|h| request.header(h))
+        .and_then(|s| epoch_s_from_json(&Value::from(s)))
+        .or_else(|| {
+            let pointer = route.run_at_pointer.as_ref()?;
+            let body: Value = serde_json::from_slice(&request.body).ok()?;
+            body.pointer(pointer).and_then(epoch_s_from_json)
+        });
+    let now = epoch_s();
+    match requested.or(route.delay_s.map(|delay_s| record.received_at + delay_s as f64)) {
+        Some(at) if at > now => Some(at),
+        _ => None,
+    }
+}
+
+/// a number, or a string containing a number
+fn epoch_s_from_json(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+pub fn epoch_s() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+/// Writes jobs to Sidekiq's Redis, optionally with offsets stored
+/// alongside, and skipping jids written recently.
+pub struct Sidekiq {
+    conn: MultiplexedConnection,
+    offsets: Option<RedisOffsets>,
+    dedupe: Option<DedupeWindow>,
+}
+
+impl Sidekiq {
+    pub fn new(conn: MultiplexedConnection, offsets: Option<RedisOffsets>, dedupe: Option<DedupeWindow>) -> Sidekiq {
+        Sidekiq { conn, offsets, dedupe }
+    }
+
+    /// the same Redis writes as Sidekiq::Client#push, or
+    /// Sidekiq::DeadSet#kill for the dead set
+    pub async fn write(&self, queue: &str, destination: Destination, job: &str) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        let _: () = write_pipeline(queue, destination, job).query_async(&mut conn).await?;
+        Ok(())
+    }
+}
+
+fn write_pipeline(queue: &str, destination: Destination, job: &str) -> redis::Pipeline {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    add_write(&mut pipe, queue, destination, job);
+    pipe
+}
+
+fn add_write(pipe: &mut redis::Pipeline, queue: &str, destination: Destination, job: &str) {
+    match destination.sorted_set() {
+        None => {
+            pipe.sadd("queues", queue)
+                .ignore()
+                .lpush(format!("queue:{}", queue), job)
+                .ignore();
+        }
+        Some((key, score)) => {
+            pipe.zadd(key, job, score).ignore();
+        }
+    }
+}
+
+fn outcome(destination: Destination) -> Outcome {
+    match destination {
+        Destination::Queue => Outcome::Enqueued,
+        Destination::Schedule(_) => Outcome::Scheduled,
+        Destination::Dead => Outcome::DeadLettered,
+    }
+}
+
+fn observe_duration(destination: Destination, start: Instant) {
+    match destination {
+        Destination::Schedule(_) => hist_time_since(&REDIS_SCHEDULE_DURATION_S, start),
+        _ => hist_time_since(&REDIS_DURATION_S, start),
+    }
+}
+
+#[async_trait]
+impl JobSink for Sidekiq {
+    async fn push(&self, route: &Route, record: &Record) -> anyhow::Result<Outcome> {
+        let jid = jid::for_record(route, record);
+        let (destination, job) = job_for_request(route, record, &jid);
+        if destination == Destination::Dead {
+            warn!("writing jid={} to the dead set: {}", jid, job["error_message"]);
+        }
+        let job = job.to_string();
+        let start = Instant::now();
+        let dedupe = self.dedupe.as_ref().map(|dedupe| (dedupe, jid.as_str()));
+        let written = match (&self.offsets, dedupe) {
+            (None, None) => self.write(&route.queue, destination, &job).await.map(|_| true),
+            (None, Some((dedupe, jid))) => dedupe.write(&route.queue, destination, &job, jid).await,
+            (Some(offsets), dedupe) => offsets.push_and_store(&route.queue, destination, &job, record, dedupe).await,
+        };
+        observe_duration(destination, start);
+        if !written? {
+            debug!(
+                "already written jid={} topic={} partition={} offset={}",
+                jid, record.topic, record.partition, record.offset
+            );
+            return Ok(Outcome::Duplicate);
+        }
+        Ok(outcome(destination))
+    }
+
+    /// Without stored offsets or dedupe, all the jobs go in one pipeline.
+    async fn push_batch(&self, route: &Route, records: &[Record]) -> Vec<anyhow::Result<Outcome>> {
+        if self.offsets.is_some() || self.dedupe.is_some() {
+            return push_in_order(records, |record| self.push(route, record)).await;
+        }
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        let mut destinations = Vec::with_capacity(records.len());
+        for record in records {
+            let jid = jid::for_record(route, record);
+            let (destination, job) = job_for_request(route, record, &jid);
+            if destination == Destination::Dead {
+                warn!("writing jid={} to the dead set: {}", jid, job["error_message"]);
+            }
+            add_write(&mut pipe, &route.queue, destination, &job.to_string());
+            destinations.push(destination);
+        }
+        let mut conn = self.conn.clone();
+        let start = Instant::now();
+        let r_write: redis::RedisResult<()> = pipe.query_async(&mut conn).await;
+        // one write, observed once in each histogram whose writes it has
+        let scheduled = destinations.iter().any(|d| matches!(d, Destination::Schedule(_)));
+        if scheduled {
+            observe_duration(Destination::Schedule(0.0), start);
+        }
+        if !scheduled || destinations.iter().any(|d| !matches!(d, Destination::Schedule(_))) {
+            observe_duration(Destination::Queue, start);
+        }
+        match r_write {
+            Ok(()) => destinations.into_iter().map(|d| Ok(outcome(d))).collect(),
+            Err(err) => {
+                let msg = err.to_string();
+                records.iter().map(|_| Err(anyhow!("{}", msg))).collect()
+            }
+        }
+    }
+
+    async fn stored_offset(&self, topic: &str, partition: i32) -> anyhow::Result<Option<i64>> {
+        match &self.offsets {
+            None => Ok(None),
+            Some(offsets) => offsets.stored(topic, partition).await,
+        }
+    }
+}
+
+impl Destination {
+    /// the sorted set and score, for destinations other than the queue
+    pub fn sorted_set(&self) -> Option<(&'static str, f64)> {
+        match self {
+            Destination::Queue => None,
+            Destination::Schedule(at) => Some(("schedule", *at)),
+            Destination::Dead => Some(("dead", epoch_s())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse;
+    use crate::DecodedRequest;
+
+    fn route(options: &str) -> Route {
+        let config = format!(r#"(("/t" . ((job-class . "T") (queue . "q") (topic . "t") {})))"#, options);
+        parse(&config).unwrap().0.remove("/t").unwrap()
+    }
+
+    fn record(body: &str, headers: Value, received_at: f64) -> Record {
+        let Value::Object(headers) = headers else {
+            panic!("headers must be an object");
+        };
+        Record {
+            topic: "t".to_owned(),
+            partition: 0,
+            offset: 7,
+            request: DecodedRequest {
+                body: body.as_bytes().to_vec(),
+                headers,
+                body_ref: None,
+            },
+            headers: Vec::new(),
+            received_at,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn delay_counts_from_receipt() {
+        let delayed = route("(delay . 30)");
+        let received_at = epoch_s() - 10.0;
+        assert_eq!(run_at(&delayed, &record("", json!({}), received_at)), Some(received_at + 30.0));
+        // already due when it's read
+        assert_eq!(run_at(&delayed, &record("", json!({}), received_at - 60.0)), None);
+        assert_eq!(run_at(&route(""), &record("", json!({}), received_at)), None);
+    }
+
+    #[test]
+    fn requested_time_wins() {
+        let route = route(r#"(delay . 30) (run-at-header . "x-run-at") (run-at-pointer . "/at")"#);
+        let now = epoch_s();
+        let at = (now + 3600.0).floor();
+        let header = record("", json!({"x-run-at": at.to_string()}), now);
+        assert_eq!(run_at(&route, &header), Some(at));
+        let body = record(&format!(r#"{{"at": {}}}"#, at + 1.0), json!({}), now);
+        assert_eq!(run_at(&route, &body), Some(at + 1.0));
+        // a time in the past runs now, rather than after the delay
+        let past = record(r#"{"at": "1000"}"#, json!({}), now);
+        assert_eq!(run_at(&route, &past), None);
+    }
+}
@@ -0,0 +1,109 @@
+//! The Kafka buffer backend.
+
+use crate::buffer::{BufferConsumer, BufferProducer, Message as BufferMessage, SendError};
+use crate::offsets::AssignmentContext;
+use anyhow::Context;
+use async_trait::async_trait;
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::stream_consumer::StreamConsumer;
+use rdkafka::consumer::{CommitMode, Consumer};
+use rdkafka::message::{Header, Headers, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::{Message, Offset, TopicPartitionList};
+use std::time::Duration;
+
+pub struct KafkaProducer {
+    producer: FutureProducer,
+}
+
+impl KafkaProducer {
+    pub fn new(kafka_url: &str) -> anyhow::Result<KafkaProducer> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", kafka_url)
+            .set("message.timeout.ms", "1000")
+            .set("connections.max.idle.ms", "30000")
+            .set("linger.ms", "10")
+            .set("compression.codec", "lz4")
+            .create()
+            .context("kafka producer")?;
+        Ok(KafkaProducer { producer })
+    }
+}
+
+#[async_trait]
+impl BufferProducer for KafkaProducer {
+    async fn send(&self, topic: &str, headers: &[(String, String)], payload: &[u8]) -> Result<(i32, i64), SendError> {
+        let headers = headers.iter().fold(OwnedHeaders::new(), |hh, (key, value)| {
+            hh.insert(Header {
+                key,
+                value: Some(value),
+            })
+        });
+        let produce_future = self
+            .producer
+            .send_result(FutureRecord::<(), [u8]>::to(topic).payload(payload).headers(headers))
+            .map_err(|(err, _)| SendError::Full(err.to_string()))?;
+        match produce_future.await {
+            Err(_cancelled) => Err(SendError::Cancelled),
+            Ok(Err((err, _))) => Err(SendError::Failed(err.to_string())),
+            Ok(Ok(delivered)) => Ok(delivered),
+        }
+    }
+}
+
+pub struct KafkaConsumer {
+    consumer: StreamConsumer<AssignmentContext>,
+}
+
+impl KafkaConsumer {
+    pub fn new(kafka_url: &str, group_id: &str, topics: &[&str]) -> anyhow::Result<KafkaConsumer> {
+        let consumer: StreamConsumer<AssignmentContext> = ClientConfig::new()
+            .set("group.id", group_id)
+            .set("bootstrap.servers", kafka_url)
+            .set("enable.partition.eof", "false")
+            .set("session.timeout.ms", "6000")
+            .set("enable.auto.commit", "false")
+            .create_with_context(AssignmentContext::default())
+            .context("kafka consumer")?;
+        consumer.subscribe(topics)?;
+        Ok(KafkaConsumer { consumer })
+    }
+}
+
+#[async_trait]
+impl BufferConsumer for KafkaConsumer {
+    async fn recv(&self) -> anyhow::Result<BufferMessage> {
+        let message = self.consumer.recv().await?;
+        Ok(BufferMessage {
+            topic: message.topic().to_owned(),
+            partition: message.partition(),
+            offset: message.offset(),
+            headers: message
+                .headers()
+                .map(|hh| {
+                    hh.iter()
+                        .filter_map(|h| Some((h.key.to_owned(), String::from_utf8_lossy(h.value?).into_owned())))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            payload: message.payload().unwrap_or_default().to_vec(),
+            timestamp_ms: message.timestamp().to_millis(),
+        })
+    }
+
+    fn take_assigned(&self, topic: &str, partition: i32) -> bool {
+        self.consumer.context().take_assigned(topic, partition)
+    }
+
+    async fn seek(&self, topic: &str, partition: i32, offset: i64) -> anyhow::Result<()> {
+        Ok(self.consumer.seek(topic, partition, Offset::Offset(offset), Duration::from_secs(5))?)
+    }
+
+    async fn commit(&self, offsets: &[(String, i32, i64)]) -> anyhow::Result<()> {
+        let mut tpl = TopicPartitionList::new();
+        for (topic, partition, next) in offsets {
+            tpl.add_partition_offset(topic, *partition, Offset::Offset(*next))?;
+        }
+        Ok(self.consumer.commit(&tpl, CommitMode::Async)?)
+    }
+}
@@ -1,7 +1,35 @@
+pub mod archive;
+pub mod avro;
+pub mod body;
+pub mod buffer;
+pub mod bullmq;
+pub mod celery;
+pub mod claimcheck;
+pub mod cloudevents;
+pub mod compression;
 pub mod config;
+pub mod content_encoding;
+pub mod disklog;
+pub mod encoding;
+pub mod faktory;
+pub mod headers;
+pub mod jid;
+pub mod job;
+pub mod kafka;
 pub mod observability;
+pub mod offsets;
+pub mod redis_buffer;
+pub mod relay;
+pub mod sink;
+pub mod stream;
+pub mod transform;
 
-use hyper::header::{HeaderMap, HeaderName};
+use anyhow::anyhow;
+use capnp::message::ReaderOptions;
+use compression::BodyCompression;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::map::Entry;
+use serde_json::{Map, Value};
 
 pub mod buffered_http_request_capnp {
     include!(concat!(env!("OUT_DIR"), "/buffered_http_request_capnp.rs"));
@@ -9,18 +37,161 @@ pub mod buffered_http_request_capnp {
 use buffered_http_request_capnp::buffered_request;
 
 pub fn encode_request(body: &[u8], headers: &HeaderMap, want: &Vec<HeaderName>) -> Vec<u8> {
+    // absent headers are left out, and repeated ones have an entry per value
+    let values: Vec<_> = want
+        .iter()
+        .flat_map(|name| headers.get_all(name).iter().map(move |value| (name, value)))
+        .collect();
+    encode_compressed_request(body, &values, None)
+}
+
+/// encode_request with the headers a route captured, compressing the body
+/// as it asks
+pub fn encode_compressed_request(
+    body: &[u8],
+    values: &[(&HeaderName, &HeaderValue)],
+    compression: Option<&BodyCompression>,
+) -> Vec<u8> {
     let mut message = ::capnp::message::Builder::new_default();
     let mut req = message.init_root::<buffered_request::Builder>();
-    req.set_body(body);
-    let mut hh = req.reborrow().init_headers(want.len().try_into().unwrap());
-    for (i, name) in want.iter().enumerate() {
-        if let Some(value) = headers.get(name) {
-            hh.reborrow().get(i as u32).set_name(name);
-            hh.reborrow().get(i as u32).set_value(value.as_bytes());
-        }
+    compression::set_body(&mut req, body, compression);
+    let mut hh = req.reborrow().init_headers(values.len().try_into().unwrap());
+    for (i, (name, value)) in values.iter().enumerate() {
+        hh.reborrow().get(i as u32).set_name(name.as_str());
+        hh.reborrow().get(i as u32).set_value(value.as_bytes());
     }
     let mut ret = Vec::new();
     // docs say: If you pass in a writer that never returns an error, then this function will never return an error.
     let _ = capnp::serialize::write_message(&mut ret, &message);
     ret
 }
+
+/// Panic unless called on a multi-thread tokio runtime, for what uses
+/// block_in_place, which panics on a current_thread one, so that shows at
+/// startup rather than on the first write.
+pub fn assert_multi_thread(what: &str) {
+    let flavor = tokio::runtime::Handle::try_current().map(|handle| handle.runtime_flavor());
+    assert!(
+        matches!(flavor, Ok(tokio::runtime::RuntimeFlavor::MultiThread)),
+        "{} needs a multi-thread tokio runtime",
+        what
+    );
+}
+
+/// Add a header value, making an array of the values of a repeated header.
+pub fn insert_header(headers: &mut Map<String, Value>, name: String, value: String) {
+    match headers.entry(name) {
+        Entry::Vacant(entry) => {
+            entry.insert(Value::String(value));
+        }
+        Entry::Occupied(mut entry) => match entry.get_mut() {
+            Value::Array(values) => values.push(Value::String(value)),
+            first => *first = Value::Array(vec![first.take(), Value::String(value)]),
+        },
+    }
+}
+
+/// a BufferedRequest, read back from Kafka
+#[derive(Clone, Debug)]
+pub struct DecodedRequest {
+    pub body: Vec<u8>,
+    /// header name => string value, or an array of the values of a
+    /// repeated header, in order
+    pub headers: Map<String, Value>,
+    /// the claim check for a body kept out of the buffer, if body is empty
+    pub body_ref: Option<String>,
+}
+
+impl DecodedRequest {
+    /// the body and the captured headers of an HTTP request
+    pub fn from_http(body: &[u8], values: &[(&HeaderName, &HeaderValue)]) -> DecodedRequest {
+        let mut kept = Map::new();
+        for (name, value) in values {
+            insert_header(&mut kept, name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned());
+        }
+        DecodedRequest {
+            body: body.to_vec(),
+            headers: kept,
+            body_ref: None,
+        }
+    }
+
+    /// the first value of a header
+    pub fn header(&self, name: &HeaderName) -> Option<&str> {
+        match self.headers.get(name.as_str())? {
+            Value::Array(values) => values.first()?.as_str(),
+            value => value.as_str(),
+        }
+    }
+
+    /// (name, value) for each value of each header
+    pub fn header_values(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter().flat_map(|(name, value)| {
+            let values = match value {
+                Value::Array(values) => values.iter().filter_map(Value::as_str).collect(),
+                value => value.as_str().into_iter().collect::<Vec<_>>(),
+            };
+            values.into_iter().map(move |v| (name.as_str(), v))
+        })
+    }
+
+    /// the BufferedRequest again, as the producer would have written it
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_compressed(None)
+    }
+
+    pub fn encode_compressed(&self, compression: Option<&BodyCompression>) -> Vec<u8> {
+        let mut ret = Vec::new();
+        let _ = capnp::serialize::write_message(&mut ret, &self.capnp_message(compression));
+        ret
+    }
+
+    /// the BufferedRequest, with capnp's packing
+    pub fn encode_packed(&self, compression: Option<&BodyCompression>) -> Vec<u8> {
+        let mut ret = Vec::new();
+        let _ = capnp::serialize_packed::write_message(&mut ret, &self.capnp_message(compression));
+        ret
+    }
+
+    fn capnp_message(&self, compression: Option<&BodyCompression>) -> capnp::message::Builder<capnp::message::HeapAllocator> {
+        let mut message = ::capnp::message::Builder::new_default();
+        let mut req = message.init_root::<buffered_request::Builder>();
+        compression::set_body(&mut req, &self.body, compression);
+        if let Some(body_ref) = &self.body_ref {
+            req.set_body_ref(body_ref.as_str());
+        }
+        let values: Vec<_> = self.header_values().collect();
+        let mut hh = req.reborrow().init_headers(values.len().try_into().unwrap());
+        for (i, (name, value)) in values.into_iter().enumerate() {
+            hh.reborrow().get(i as u32).set_name(name);
+            hh.reborrow().get(i as u32).set_value(value.as_bytes());
+        }
+        message
+    }
+}
+
+pub fn decode_request(o_bytes: Option<&[u8]>) -> anyhow::Result<DecodedRequest> {
+    let bytes = o_bytes.ok_or(anyhow!("kafka message has no payload"))?;
+    decode_capnp(capnp::serialize::read_message(bytes, ReaderOptions::new())?)
+}
+
+/// a BufferedRequest written with capnp's packing
+pub fn decode_packed_request(bytes: &[u8]) -> anyhow::Result<DecodedRequest> {
+    decode_capnp(capnp::serialize_packed::read_message(bytes, ReaderOptions::new())?)
+}
+
+fn decode_capnp<S: capnp::message::ReaderSegments>(reader: capnp::message::Reader<S>) -> anyhow::Result<DecodedRequest> {
+    let buf_req = reader.get_root::<buffered_request::Reader>()?;
+    let body = compression::get_body(&buf_req)?;
+    let mut headers = Map::new();
+    for h in buf_req.get_headers()? {
+        let name = h.get_name()?.to_str()?.to_owned();
+        let value = String::from_utf8(h.get_value()?.to_vec())?;
+        insert_header(&mut headers, name, value);
+    }
+    let body_ref = match buf_req.has_body_ref() {
+        true => Some(buf_req.get_body_ref()?.to_str()?.to_owned()),
+        false => None,
+    };
+    Ok(DecodedRequest { body, headers, body_ref })
+}
@@ -0,0 +1,137 @@
+//! Consumer offsets stored in Redis, next to the Sidekiq queues.
+//!
+//! The job push and the offset update run in one Lua script, with the
+//! dedupe window's mark of the jid if there is one, so a crash between them
+//! cannot enqueue the same Kafka message twice.
+
+use rdkafka::consumer::{ConsumerContext, Rebalance};
+use rdkafka::ClientContext;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, Script};
+use crate::jid::{self, DedupeWindow};
+use crate::job::Destination;
+use crate::sink::Record;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+// KEYS: offsets hash, queue list, set of queue names, sorted set (schedule or
+//       dead), jid key
+// ARGV: hash field, offset, queue name, job json, score (empty to enqueue now),
+//       dedupe window s (empty for none)
+// returns 0 if the offset was already done, or the jid written within the
+// window, otherwise 1
+const PUSH_AND_STORE_OFFSET: &str = r#"
+local stored = tonumber(redis.call('HGET', KEYS[1], ARGV[1]))
+local offset = tonumber(ARGV[2])
+if stored and offset < stored then
+    return 0
+end
+if ARGV[6] ~= '' and not redis.call('SET', KEYS[5], 1, 'NX', 'EX', ARGV[6]) then
+    redis.call('HSET', KEYS[1], ARGV[1], offset + 1)
+    return 0
+end
+if ARGV[5] == '' then
+    redis.call('SADD', KEYS[3], ARGV[3])
+    redis.call('LPUSH', KEYS[2], ARGV[4])
+else
+    redis.call('ZADD', KEYS[4], ARGV[5], ARGV[4])
+end
+redis.call('HSET', KEYS[1], ARGV[1], offset + 1)
+return 1
+"#;
+
+pub struct RedisOffsets {
+    conn: MultiplexedConnection,
+    /// hash of topic:partition => next offset to read
+    key: String,
+    script: Script,
+}
+
+impl RedisOffsets {
+    pub fn new(conn: MultiplexedConnection, group_id: &str) -> RedisOffsets {
+        RedisOffsets {
+            conn,
+            key: format!("kafka-buffer:offsets:{}", group_id),
+            script: Script::new(PUSH_AND_STORE_OFFSET),
+        }
+    }
+
+    /// the next offset to read, if we have pushed anything from this partition
+    pub async fn stored(&self, topic: &str, partition: i32) -> anyhow::Result<Option<i64>> {
+        let mut conn = self.conn.clone();
+        let offset: Option<i64> = conn.hget(&self.key, field(topic, partition)).await?;
+        Ok(offset)
+    }
+
+    /// Write a Sidekiq job and record the offset it came from as done.
+    /// Returns false without writing if the offset was already done, or
+    /// the jid was written within the dedupe window.
+    pub async fn push_and_store(
+        &self,
+        queue: &str,
+        destination: Destination,
+        job: &str,
+        record: &Record,
+        dedupe: Option<(&DedupeWindow, &str)>,
+    ) -> anyhow::Result<bool> {
+        let mut conn = self.conn.clone();
+        let sorted_set = destination.sorted_set();
+        let mut invocation = self.script.prepare_invoke();
+        invocation
+            .key(&self.key)
+            .key(format!("queue:{}", queue))
+            .key("queues")
+            .key(sorted_set.map(|(key, _)| key).unwrap_or("schedule"))
+            .key(dedupe.map(|(_, id)| jid::key(id)).unwrap_or_default())
+            .arg(field(&record.topic, record.partition))
+            .arg(record.offset)
+            .arg(queue)
+            .arg(job)
+            .arg(sorted_set.map(|(_, score)| score.to_string()).unwrap_or_default())
+            .arg(dedupe.map(|(window, _)| window.window_s.to_string()).unwrap_or_default());
+        let pushed: i64 = invocation.invoke_async(&mut conn).await?;
+        Ok(pushed == 1)
+    }
+}
+
+fn field(topic: &str, partition: i32) -> String {
+    format!("{}:{}", topic, partition)
+}
+
+/// Remembers newly assigned partitions, so the consumer can seek to the
+/// offsets stored in Redis when it sees their first message.
+#[derive(Default)]
+pub struct AssignmentContext {
+    assigned: Mutex<HashSet<(String, i32)>>,
+}
+
+impl AssignmentContext {
+    /// true the first time it is called for a partition after each assignment
+    pub fn take_assigned(&self, topic: &str, partition: i32) -> bool {
+        self.assigned
+            .lock()
+            .unwrap()
+            .remove(&(topic.to_owned(), partition))
+    }
+}
+
+impl ClientContext for AssignmentContext {}
+
+impl ConsumerContext for AssignmentContext {
+    fn post_rebalance<'a>(&self, rebalance: &Rebalance<'a>) {
+        let mut assigned = self.assigned.lock().unwrap();
+        match rebalance {
+            Rebalance::Assign(tpl) => {
+                for elem in tpl.elements() {
+                    assigned.insert((elem.topic().to_owned(), elem.partition()));
+                }
+            }
+            Rebalance::Revoke(tpl) => {
+                for elem in tpl.elements() {
+                    assigned.remove(&(elem.topic().to_owned(), elem.partition()));
+                }
+            }
+            Rebalance::Error(_) => (),
+        }
+    }
+}
@@ -0,0 +1,351 @@
+//! Redis Streams as the buffer, for teams who have Redis and not Kafka.
+//!
+//! Each topic is a stream with one partition, and each payload is the
+//! `payload` field of an entry, with each header in a `header:<name>`
+//! field.  The consumer reads with XREADGROUP, and committing an offset
+//! XACKs the entries before it.  Entries left pending by a consumer which
+//! died are taken over with XAUTOCLAIM.
+//!
+//! Offsets are stream ids packed into an i64: the millisecond part, shifted
+//! left 20 bits, plus the sequence number.
+
+use crate::buffer::{BufferConsumer, BufferProducer, Message, SendError};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use redis::aio::MultiplexedConnection;
+use redis::streams::{StreamAutoClaimOptions, StreamAutoClaimReply, StreamId, StreamMaxlen, StreamReadOptions, StreamReadReply};
+use redis::AsyncCommands;
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::*;
+
+lazy_static! {
+    static ref REDIS_BUFFER_CLAIMED: IntCounter =
+        register_int_counter!("redis_buffer_claimed", "number of stream entries taken over from other consumers").unwrap();
+}
+
+/// how often the consumer looks for new entries
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// entries read at once from each stream
+const READ_COUNT: usize = 100;
+/// how often the consumer looks for entries to claim
+const CLAIM_INTERVAL: Duration = Duration::from_secs(10);
+
+const SEQ_BITS: u32 = 20;
+
+const HEADER_FIELD_PREFIX: &str = "header:";
+
+pub fn offset_from_id(id: &str) -> anyhow::Result<i64> {
+    let (ms, seq) = id.split_once('-').ok_or(anyhow!("invalid stream id {}", id))?;
+    let (ms, seq): (i64, i64) = (ms.parse()?, seq.parse()?);
+    if seq >= 1 << SEQ_BITS {
+        return Err(anyhow!("stream id {} has too large a sequence number to be an offset", id));
+    }
+    Ok(ms << SEQ_BITS | seq)
+}
+
+pub fn id_from_offset(offset: i64) -> String {
+    format!("{}-{}", offset >> SEQ_BITS, offset & ((1 << SEQ_BITS) - 1))
+}
+
+pub struct RedisBufferProducer {
+    conn: MultiplexedConnection,
+    /// trim each stream to about this many entries
+    maxlen: Option<usize>,
+}
+
+impl RedisBufferProducer {
+    pub fn new(conn: MultiplexedConnection, maxlen: Option<usize>) -> RedisBufferProducer {
+        RedisBufferProducer { conn, maxlen }
+    }
+}
+
+#[async_trait]
+impl BufferProducer for RedisBufferProducer {
+    async fn send(&self, topic: &str, headers: &[(String, String)], payload: &[u8]) -> Result<(i32, i64), SendError> {
+        let mut conn = self.conn.clone();
+        let mut fields: Vec<(String, &[u8])> = vec![("payload".to_string(), payload)];
+        for (name, value) in headers {
+            fields.push((format!("{}{}", HEADER_FIELD_PREFIX, name), value.as_bytes()));
+        }
+        let r_id: redis::RedisResult<String> = match self.maxlen {
+            None => conn.xadd(topic, "*", &fields).await,
+            Some(maxlen) => conn.xadd_maxlen(topic, StreamMaxlen::Approx(maxlen), "*", &fields).await,
+        };
+        let id = r_id.map_err(|err| SendError::Failed(err.to_string()))?;
+        let offset = offset_from_id(&id).map_err(|err| SendError::Failed(err.to_string()))?;
+        Ok((0, offset))
+    }
+}
+
+/// where the consumer is in one stream
+struct Reader {
+    topic: String,
+    /// Read our own pending entries after `last_id`, rather than new
+    /// entries.  Set at start, after a seek back, and while a read of new
+    /// entries is in flight, in case the read is cancelled and its entries
+    /// are pending but were never received.
+    history: bool,
+    /// the last entry received
+    last_id: String,
+    /// after a seek forward, entries before this offset are acked unread
+    skip_below: i64,
+    /// received and not yet acked
+    pending: BTreeSet<i64>,
+}
+
+struct ConsumerState {
+    readers: Vec<Reader>,
+    received: VecDeque<Message>,
+    last_claim: Instant,
+}
+
+pub struct RedisBufferConsumer {
+    conn: MultiplexedConnection,
+    group_id: String,
+    /// The same after a restart, to read our own pending entries again, and
+    /// unique among running consumers.
+    consumer_name: String,
+    /// claim entries pending this long in other consumers
+    claim_idle: Duration,
+    state: Mutex<ConsumerState>,
+    /// topics whose first message hasn't been received
+    assigned: std::sync::Mutex<HashSet<String>>,
+}
+
+impl RedisBufferConsumer {
+    /// Creates the consumer group on each stream, if it doesn't exist,
+    /// starting from the first entry.
+    pub async fn new(
+        conn: MultiplexedConnection,
+        group_id: &str,
+        consumer_name: &str,
+        claim_idle: Duration,
+        topics: &[&str],
+    ) -> anyhow::Result<RedisBufferConsumer> {
+        let mut c = conn.clone();
+        for topic in topics {
+            let r_create: redis::RedisResult<()> = c.xgroup_create_mkstream(*topic, group_id, "0").await;
+            match r_create {
+                Err(err) if err.code() == Some("BUSYGROUP") => (),
+                r => r?,
+            }
+        }
+        Ok(RedisBufferConsumer {
+            conn,
+            group_id: group_id.to_owned(),
+            consumer_name: consumer_name.to_owned(),
+            claim_idle,
+            state: Mutex::new(ConsumerState {
+                readers: topics
+                    .iter()
+                    .map(|topic| Reader {
+                        topic: topic.to_string(),
+                        history: true,
+                        last_id: "0-0".to_string(),
+                        skip_below: 0,
+                        pending: BTreeSet::new(),
+                    })
+                    .collect(),
+                received: VecDeque::new(),
+                last_claim: Instant::now(),
+            }),
+            assigned: std::sync::Mutex::new(topics.iter().map(|t| t.to_string()).collect()),
+        })
+    }
+
+    /// Queue an entry to be received, or ack it if it can't be.
+    async fn receive(&self, state: &mut ConsumerState, topic: &str, entry: StreamId) -> anyhow::Result<()> {
+        let offset = offset_from_id(&entry.id)?;
+        let reader = state
+            .readers
+            .iter_mut()
+            .find(|r| r.topic == topic)
+            .ok_or(anyhow!("not subscribed to stream {}", topic))?;
+        if offset_from_id(&reader.last_id)? < offset {
+            reader.last_id = entry.id.clone();
+        }
+        let payload: Option<Vec<u8>> = entry.get("payload");
+        match payload {
+            Some(payload) if offset >= reader.skip_below => {
+                reader.pending.insert(offset);
+                state.received.push_back(Message {
+                    topic: topic.to_owned(),
+                    partition: 0,
+                    offset,
+                    headers: entry
+                        .map
+                        .iter()
+                        .filter_map(|(field, value)| {
+                            let name = field.strip_prefix(HEADER_FIELD_PREFIX)?;
+                            Some((name.to_owned(), redis::from_redis_value(value).ok()?))
+                        })
+                        .collect(),
+                    payload,
+                    // the millisecond part of the id is when it was added
+                    timestamp_ms: Some(offset >> SEQ_BITS),
+                });
+            }
+            // trimmed from the stream while pending, or skipped
+            _ => {
+                let mut conn = self.conn.clone();
+                let _: i64 = conn.xack(topic, &self.group_id, &[&entry.id]).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn read(&self, state: &mut ConsumerState) -> anyhow::Result<()> {
+        let keys: Vec<String> = state.readers.iter().map(|r| r.topic.clone()).collect();
+        let ids: Vec<String> = state
+            .readers
+            .iter()
+            .map(|r| if r.history { r.last_id.clone() } else { ">".to_string() })
+            .collect();
+        for reader in state.readers.iter_mut() {
+            reader.history = true;
+        }
+        let options = StreamReadOptions::default()
+            .group(&self.group_id, &self.consumer_name)
+            .count(READ_COUNT);
+        let mut conn = self.conn.clone();
+        let reply: Option<StreamReadReply> = conn.xread_options(&keys, &ids, &options).await?;
+        let mut read = HashSet::new();
+        for stream in reply.map(|r| r.keys).unwrap_or_default() {
+            if !stream.ids.is_empty() {
+                read.insert(stream.key.clone());
+            }
+            for entry in stream.ids {
+                self.receive(state, &stream.key, entry).await?;
+            }
+        }
+        // history is done when it's empty, and new entries were all received
+        for (reader, id) in state.readers.iter_mut().zip(ids) {
+            if id == ">" || !read.contains(&reader.topic) {
+                reader.history = false;
+            }
+        }
+        Ok(())
+    }
+
+    async fn claim(&self, state: &mut ConsumerState) -> anyhow::Result<()> {
+        state.last_claim = Instant::now();
+        let topics: Vec<String> = state.readers.iter().map(|r| r.topic.clone()).collect();
+        let mut conn = self.conn.clone();
+        for topic in topics {
+            let reply: StreamAutoClaimReply = conn
+                .xautoclaim_options(
+                    &topic,
+                    &self.group_id,
+                    &self.consumer_name,
+                    self.claim_idle.as_millis() as u64,
+                    "0-0",
+                    StreamAutoClaimOptions::default().count(READ_COUNT),
+                )
+                .await?;
+            for entry in reply.claimed {
+                info!("claimed stream={} id={}", topic, entry.id);
+                REDIS_BUFFER_CLAIMED.inc();
+                self.receive(state, &topic, entry).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BufferConsumer for RedisBufferConsumer {
+    async fn recv(&self) -> anyhow::Result<Message> {
+        loop {
+            {
+                let mut state = self.state.lock().await;
+                if state.received.is_empty() && state.last_claim.elapsed() >= CLAIM_INTERVAL {
+                    self.claim(&mut state).await?;
+                }
+                if state.received.is_empty() {
+                    self.read(&mut state).await?;
+                }
+                if let Some(message) = state.received.pop_front() {
+                    return Ok(message);
+                }
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    fn take_assigned(&self, topic: &str, _partition: i32) -> bool {
+        self.assigned.lock().unwrap().remove(topic)
+    }
+
+    /// Back, to read pending entries again, or forward, to ack entries
+    /// before `offset` as they arrive.
+    async fn seek(&self, topic: &str, _partition: i32, offset: i64) -> anyhow::Result<()> {
+        let mut state = self.state.lock().await;
+        state.received.retain(|m| m.topic != topic);
+        let reader = state
+            .readers
+            .iter_mut()
+            .find(|r| r.topic == topic)
+            .ok_or(anyhow!("not subscribed to stream {}", topic))?;
+        if offset <= offset_from_id(&reader.last_id)? {
+            reader.last_id = id_from_offset(offset - 1);
+            reader.history = true;
+        }
+        reader.skip_below = offset;
+        Ok(())
+    }
+
+    /// XACK each stream's received entries before the committed offset.
+    async fn commit(&self, offsets: &[(String, i32, i64)]) -> anyhow::Result<()> {
+        let mut state = self.state.lock().await;
+        let mut conn = self.conn.clone();
+        for (topic, _partition, next) in offsets {
+            let Some(reader) = state.readers.iter_mut().find(|r| r.topic == *topic) else {
+                continue;
+            };
+            let done: Vec<i64> = reader.pending.range(..next).copied().collect();
+            if done.is_empty() {
+                continue;
+            }
+            let ids: Vec<String> = done.iter().map(|o| id_from_offset(*o)).collect();
+            let _: i64 = conn.xack(topic, &self.group_id, &ids).await?;
+            for offset in done {
+                reader.pending.remove(&offset);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_round_trip() {
+        for id in ["0-0", "0-1", "1526919030474-55", "1700000000000-1048575"] {
+            assert_eq!(id_from_offset(offset_from_id(id).unwrap()), id);
+        }
+        assert_eq!(offset_from_id("1-0").unwrap(), 1 << SEQ_BITS);
+    }
+
+    #[test]
+    fn offsets_keep_order() {
+        let ids = ["1700000000000-0", "1700000000000-1", "1700000000000-1048575", "1700000000001-0"];
+        let offsets: Vec<i64> = ids.iter().map(|id| offset_from_id(id).unwrap()).collect();
+        assert!(offsets.windows(2).all(|w| w[0] < w[1]));
+        // the millisecond part is when the entry was added
+        assert_eq!(offsets[0] >> SEQ_BITS, 1_700_000_000_000);
+    }
+
+    #[test]
+    fn invalid_ids() {
+        assert!(offset_from_id("1700000000000-1048576").is_err());
+        assert!(offset_from_id("1700000000000").is_err());
+        assert!(offset_from_id("a-0").is_err());
+        assert!(offset_from_id("0-b").is_err());
+    }
+}
@@ -0,0 +1,265 @@
+//! Replays buffered requests as HTTP POSTs to an upstream, for receivers
+//! which are plain HTTP services that can't take bursts.
+
+use crate::config::Route;
+use crate::jid;
+use crate::observability::hist_time_since;
+use crate::sink::{JobSink, Outcome, Record};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use futures::future::join_all;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::header::{HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, HOST, RETRY_AFTER, TRANSFER_ENCODING};
+use hyper::{Request, StatusCode};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use lazy_static::lazy_static;
+use prometheus::{register_histogram, register_int_counter, Histogram, IntCounter};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tracing::*;
+
+lazy_static! {
+    static ref HTTP_RELAY_DURATION_S: Histogram =
+        register_histogram!("http_relay_duration_s", "duration of requests to upstreams").unwrap();
+    static ref HTTP_RELAY_RETRIES: IntCounter =
+        register_int_counter!("http_relay_retries", "number of requests to upstreams which were tried again").unwrap();
+}
+
+/// the longest wait between tries, even if the upstream asks for more
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// what to do with requests the upstream rejects, with a status other than
+/// 2xx, 5xx, or 429, and requests which can't be sent
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rejected {
+    /// try again like a 5xx, then fail, so the consumer reads the request
+    /// again and the partition waits until the upstream takes it
+    Retry,
+    /// dead letter the request, which is then kept only in Kafka
+    Skip,
+}
+
+pub struct HttpRelay {
+    client: Client<HttpConnector, Full<Bytes>>,
+    /// requests in flight, across all routes
+    permits: Semaphore,
+    timeout: Duration,
+    /// tries after the first
+    retries: u32,
+    /// wait before the first retry, doubling each time
+    backoff: Duration,
+}
+
+impl HttpRelay {
+    pub fn new(concurrency: usize, timeout: Duration, retries: u32, backoff: Duration) -> HttpRelay {
+        HttpRelay {
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            permits: Semaphore::new(concurrency),
+            timeout,
+            retries,
+            backoff,
+        }
+    }
+
+    /// One try.  Ok is the upstream's status, and its Retry-After in seconds.
+    async fn send(&self, request: Request<Full<Bytes>>) -> anyhow::Result<(StatusCode, Option<u64>)> {
+        let _permit = self.permits.acquire().await?;
+        let start = Instant::now();
+        let r_response = tokio::time::timeout(self.timeout, self.client.request(request)).await;
+        hist_time_since(&HTTP_RELAY_DURATION_S, start);
+        let response = r_response.map_err(|_| anyhow!("no response within {:?}", self.timeout))??;
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|s| s.parse().ok());
+        // read the body so the connection can be reused
+        let _ = response.into_body().collect().await;
+        Ok((status, retry_after))
+    }
+}
+
+/// the buffered request, with its captured headers and an id the upstream
+/// can use to drop repeats
+fn upstream_request(upstream: &str, id: &str, record: &Record) -> anyhow::Result<Request<Full<Bytes>>> {
+    let mut request = Request::post(upstream).header("kafka-buffer-id", id);
+    for (name, value) in record.request.header_values() {
+        let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) else {
+            continue;
+        };
+        // these describe the original connection, not this one
+        if [HOST, CONTENT_LENGTH, TRANSFER_ENCODING, CONNECTION].contains(&name) {
+            continue;
+        }
+        request = request.header(name, value);
+    }
+    Ok(request.body(Full::new(Bytes::from(record.request.body.clone())))?)
+}
+
+/// answers which may change on retry
+fn retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+#[async_trait]
+impl JobSink for HttpRelay {
+    /// Ok once the upstream answers 2xx.  Other answers are tried again,
+    /// unless the route's rejected attribute skips answers other than 5xx
+    /// and 429.
+    async fn push(&self, route: &Route, record: &Record) -> anyhow::Result<Outcome> {
+        let upstream = route
+            .upstream
+            .as_ref()
+            .ok_or(anyhow!("route for topic {} has no upstream", route.topic))?;
+        let id = jid::for_record(route, record);
+        if let Some(reason) = &record.error {
+            return match route.rejected {
+                Rejected::Retry => Err(anyhow!("can't send id={} to {}: {}", id, upstream, reason)),
+                Rejected::Skip => {
+                    warn!("not sending id={} to {}: {}", id, upstream, reason);
+                    Ok(Outcome::DeadLettered)
+                }
+            };
+        }
+        let mut backoff = self.backoff;
+        let mut tries = 0;
+        loop {
+            tries += 1;
+            let retry_after = match self.send(upstream_request(upstream.as_str(), &id, record)?).await {
+                Ok((status, _)) if status.is_success() => return Ok(Outcome::Enqueued),
+                Ok((status, _)) if route.rejected == Rejected::Skip && !retryable(status) => {
+                    warn!("{} rejected id={} with {}, not trying again", upstream, id, status);
+                    return Ok(Outcome::DeadLettered);
+                }
+                Ok((status, retry_after)) => {
+                    if tries > self.retries {
+                        return Err(anyhow!("{} answered {} after {} tries", upstream, status, tries));
+                    }
+                    warn!("{} answered {} for id={}, trying again", upstream, status, id);
+                    retry_after.map(Duration::from_secs)
+                }
+                Err(err) => {
+                    if tries > self.retries {
+                        return Err(anyhow!("{} failed after {} tries: {}", upstream, tries, err));
+                    }
+                    warn!("{} failed for id={}, trying again: {}", upstream, id, err);
+                    None
+                }
+            };
+            HTTP_RELAY_RETRIES.inc();
+            tokio::time::sleep(retry_after.unwrap_or(backoff).min(MAX_BACKOFF)).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// all at once, up to the concurrency limit
+    async fn push_batch(&self, route: &Route, records: &[Record]) -> Vec<anyhow::Result<Outcome>> {
+        join_all(records.iter().map(|record| self.push(route, record))).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse;
+    use crate::DecodedRequest;
+    use hyper::body::Incoming;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::Response;
+    use hyper_util::rt::TokioIo;
+    use serde_json::Map;
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    /// an upstream which answers every request with `status`, and the
+    /// number of requests it has had
+    async fn upstream(status: StatusCode) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let counted = counted.clone();
+                let service = service_fn(move |_: Request<Incoming>| {
+                    counted.fetch_add(1, Ordering::SeqCst);
+                    let response = Response::builder().status(status).body(Full::new(Bytes::new()));
+                    async move { Ok::<Response<Full<Bytes>>, Infallible>(response.unwrap()) }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        (url, requests)
+    }
+
+    fn route(upstream: &str, rejected: &str) -> Route {
+        let config = format!(
+            r#"(("/t" . ((sink . "http") (upstream . "{}") (topic . "t") (rejected . "{}"))))"#,
+            upstream, rejected
+        );
+        parse(&config).unwrap().0.remove("/t").unwrap()
+    }
+
+    fn record(error: Option<&str>) -> Record {
+        Record {
+            topic: "t".to_owned(),
+            partition: 0,
+            offset: 7,
+            request: DecodedRequest {
+                body: b"{}".to_vec(),
+                headers: Map::new(),
+                body_ref: None,
+            },
+            headers: Vec::new(),
+            received_at: 0.0,
+            error: error.map(str::to_owned),
+        }
+    }
+
+    fn relay() -> HttpRelay {
+        HttpRelay::new(4, Duration::from_secs(5), 1, Duration::from_millis(1))
+    }
+
+    #[tokio::test]
+    async fn rejected_is_retried_by_default() {
+        let (url, requests) = upstream(StatusCode::BAD_REQUEST).await;
+        let config = format!(r#"(("/t" . ((sink . "http") (upstream . "{}") (topic . "t"))))"#, url);
+        let route = parse(&config).unwrap().0.remove("/t").unwrap();
+        assert!(relay().push(&route, &record(None)).await.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn rejected_is_skipped() {
+        let (url, requests) = upstream(StatusCode::BAD_REQUEST).await;
+        let outcome = relay().push(&route(&url, "skip"), &record(None)).await.unwrap();
+        assert_eq!(outcome, Outcome::DeadLettered);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried_even_when_skipping() {
+        let (url, requests) = upstream(StatusCode::SERVICE_UNAVAILABLE).await;
+        assert!(relay().push(&route(&url, "skip"), &record(None)).await.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn record_errors_follow_rejected() {
+        let (url, requests) = upstream(StatusCode::OK).await;
+        let swept = record(Some("claim check file:///tmp/bodies/t/1 is gone"));
+        assert!(relay().push(&route(&url, "retry"), &swept).await.is_err());
+        let outcome = relay().push(&route(&url, "skip"), &swept).await.unwrap();
+        assert_eq!(outcome, Outcome::DeadLettered);
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+        assert_eq!(relay().push(&route(&url, "skip"), &record(None)).await.unwrap(), Outcome::Enqueued);
+    }
+}
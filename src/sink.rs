@@ -0,0 +1,92 @@
+//! Where the consumer writes jobs.  Each route picks a sink in config.
+
+use crate::body::body_args;
+use crate::config::Route;
+use crate::DecodedRequest;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use serde_json::Value;
+use std::future::Future;
+
+/// a decoded request, and the Kafka record it came from
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub request: DecodedRequest,
+    /// the buffer message's own headers, like its encoding, which the
+    /// archive keeps for replay
+    pub headers: Vec<(String, String)>,
+    /// when the request was buffered, in seconds since the epoch
+    pub received_at: f64,
+    /// why the request can't become a job, like a claim-checked body which
+    /// was swept, so the sink dead letters it
+    pub error: Option<String>,
+}
+
+impl Record {
+    /// the job arguments from the body, before the headers, or why the
+    /// request must be dead lettered
+    pub fn body_args(&self, route: &Route) -> Result<Vec<Value>, String> {
+        match &self.error {
+            Some(reason) => Err(reason.clone()),
+            None => body_args(&route.body, &self.request.body),
+        }
+    }
+}
+
+/// what happened to one record
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Enqueued,
+    Scheduled,
+    /// the request couldn't become a job, and was kept for inspection
+    DeadLettered,
+    /// already written, so skipped
+    Duplicate,
+}
+
+/// Push records one at a time, with none after a partition's first
+/// failure.  Sinks which store offsets need this, since writing a later
+/// record would mark the failed one done, and it would be skipped when it
+/// is read again.
+pub async fn push_in_order<'a, F, Fut>(records: &'a [Record], mut push: F) -> Vec<anyhow::Result<Outcome>>
+where
+    F: FnMut(&'a Record) -> Fut,
+    Fut: Future<Output = anyhow::Result<Outcome>>,
+{
+    let mut results = Vec::with_capacity(records.len());
+    let mut failed: Vec<(&str, i32)> = Vec::new();
+    for record in records {
+        if failed.contains(&(record.topic.as_str(), record.partition)) {
+            results.push(Err(anyhow!("not written, after an earlier failure in partition {}", record.partition)));
+            continue;
+        }
+        let result = push(record).await;
+        if result.is_err() {
+            failed.push((record.topic.as_str(), record.partition));
+        }
+        results.push(result);
+    }
+    results
+}
+
+#[async_trait]
+pub trait JobSink: Send + Sync {
+    /// Write the job for one record.  Err means the job was not written.
+    async fn push(&self, route: &Route, record: &Record) -> anyhow::Result<Outcome>;
+
+    /// Write jobs for records from one route, returning one result per
+    /// record, in order.  Sinks which can write several jobs in one round
+    /// trip should override this.
+    async fn push_batch(&self, route: &Route, records: &[Record]) -> Vec<anyhow::Result<Outcome>> {
+        push_in_order(records, |record| self.push(route, record)).await
+    }
+
+    /// For sinks which store consumer offsets themselves, the next offset
+    /// to read from a partition.
+    async fn stored_offset(&self, _topic: &str, _partition: i32) -> anyhow::Result<Option<i64>> {
+        Ok(None)
+    }
+}
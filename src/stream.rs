@@ -0,0 +1,214 @@
+//! Requests as Redis Streams entries, for consumers which read streams
+//! rather than job queues.  The route's queue is the stream key.
+
+use crate::config::Route;
+use crate::jid;
+use crate::observability::hist_time_since;
+use crate::sink::{push_in_order, JobSink, Outcome, Record};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use prometheus::{register_histogram, Histogram};
+use redis::aio::MultiplexedConnection;
+use redis::streams::StreamMaxlen;
+use redis::Script;
+use serde_json::Value;
+use std::time::Instant;
+
+lazy_static! {
+    static ref STREAM_DURATION_S: Histogram =
+        register_histogram!("stream_duration_s", "duration of writes to Redis streams").unwrap();
+}
+
+// KEYS: stream, hash of the last offset written from each partition
+// ARGV: topic:partition, offset, maxlen (0 for no trimming), then field
+//       value pairs
+// returns 0 if the offset was already written, otherwise 1
+// Unit tests have no Redis, so this is checked by hand: with `just redis`
+// and a (dedupe . true) route, stop the consumer before it commits, start
+// it again, and `just stream-entries` shows each offset once.
+const XADD_ONCE: &str = r#"
+local last = redis.call('HGET', KEYS[2], ARGV[1])
+if last and tonumber(last) >= tonumber(ARGV[2]) then
+    return 0
+end
+local maxlen = tonumber(ARGV[3])
+if maxlen > 0 then
+    redis.call('XADD', KEYS[1], 'MAXLEN', '~', maxlen, '*', unpack(ARGV, 4))
+else
+    redis.call('XADD', KEYS[1], '*', unpack(ARGV, 4))
+end
+redis.call('HSET', KEYS[2], ARGV[1], ARGV[2])
+return 1
+"#;
+
+pub struct RedisStream {
+    conn: MultiplexedConnection,
+    script: Script,
+}
+
+impl RedisStream {
+    pub fn new(conn: MultiplexedConnection) -> RedisStream {
+        RedisStream {
+            conn,
+            script: Script::new(XADD_ONCE),
+        }
+    }
+
+    /// one entry, unless its offset was already written
+    async fn xadd_once(&self, route: &Route, record: &Record) -> anyhow::Result<Outcome> {
+        let mut conn = self.conn.clone();
+        let mut invocation = self.script.prepare_invoke();
+        invocation
+            .key(&route.queue)
+            .key(offsets_key(&route.queue))
+            .arg(format!("{}:{}", record.topic, record.partition))
+            .arg(record.offset)
+            .arg(route.maxlen.unwrap_or(0))
+            .arg(entry(route, record));
+        let start = Instant::now();
+        let r_added: redis::RedisResult<i64> = invocation.invoke_async(&mut conn).await;
+        hist_time_since(&STREAM_DURATION_S, start);
+        match r_added? {
+            0 => Ok(Outcome::Duplicate),
+            _ => Ok(outcome(record)),
+        }
+    }
+}
+
+/// the hash next to a stream, for routes which dedupe
+fn offsets_key(stream: &str) -> String {
+    format!("{}:kafka-offsets", stream)
+}
+
+/// The entry's fields.  The body is written as is, since Redis values
+/// needn't be UTF-8.  Records with an error have an error field, for
+/// readers to set aside.
+fn entry(route: &Route, record: &Record) -> Vec<(&'static str, Vec<u8>)> {
+    let mut fields = vec![
+        ("id", jid::for_record(route, record).into_bytes()),
+        ("topic", record.topic.clone().into_bytes()),
+        ("partition", record.partition.to_string().into_bytes()),
+        ("offset", record.offset.to_string().into_bytes()),
+        ("headers", Value::Object(record.request.headers.clone()).to_string().into_bytes()),
+        ("body", record.request.body.clone()),
+    ];
+    if let Some(reason) = &record.error {
+        fields.push(("error", reason.clone().into_bytes()));
+    }
+    fields
+}
+
+fn outcome(record: &Record) -> Outcome {
+    match record.error {
+        None => Outcome::Enqueued,
+        Some(_) => Outcome::DeadLettered,
+    }
+}
+
+fn add_xadd(pipe: &mut redis::Pipeline, route: &Route, record: &Record) {
+    let xadd = pipe.cmd("XADD").arg(&route.queue);
+    if let Some(maxlen) = route.maxlen {
+        xadd.arg(StreamMaxlen::Approx(maxlen));
+    }
+    xadd.arg("*").arg(entry(route, record)).ignore();
+}
+
+#[async_trait]
+impl JobSink for RedisStream {
+    async fn push(&self, route: &Route, record: &Record) -> anyhow::Result<Outcome> {
+        self.push_batch(route, std::slice::from_ref(record)).await.remove(0)
+    }
+
+    /// Routes which dedupe write each entry with the offset check,
+    /// otherwise all the entries go in one pipeline.
+    async fn push_batch(&self, route: &Route, records: &[Record]) -> Vec<anyhow::Result<Outcome>> {
+        if route.dedupe {
+            return push_in_order(records, |record| self.xadd_once(route, record)).await;
+        }
+        let mut conn = self.conn.clone();
+        let mut pipe = redis::pipe();
+        for record in records {
+            add_xadd(&mut pipe, route, record);
+        }
+        let start = Instant::now();
+        let r_write: redis::RedisResult<()> = pipe.query_async(&mut conn).await;
+        hist_time_since(&STREAM_DURATION_S, start);
+        match r_write {
+            Ok(()) => records.iter().map(|record| Ok(outcome(record))).collect(),
+            Err(err) => {
+                let msg = err.to_string();
+                records.iter().map(|_| Err(anyhow!("{}", msg))).collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse;
+    use crate::DecodedRequest;
+    use serde_json::{json, Map};
+
+    fn route(options: &str) -> Route {
+        let config = format!(r#"(("/t" . ((sink . "redis-stream") (queue . "events") (topic . "t") {})))"#, options);
+        parse(&config).unwrap().0.remove("/t").unwrap()
+    }
+
+    fn record(error: Option<&str>) -> Record {
+        let mut headers = Map::new();
+        headers.insert("x-request-id".to_owned(), json!("abc"));
+        Record {
+            topic: "t".to_owned(),
+            partition: 2,
+            offset: 7,
+            request: DecodedRequest {
+                body: vec![b'{', b'}', 0xff],
+                headers,
+                body_ref: None,
+            },
+            headers: Vec::new(),
+            received_at: 0.0,
+            error: error.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn entry_fields() {
+        assert_eq!(
+            entry(&route(""), &record(None)),
+            vec![
+                ("id", b"t-2-7".to_vec()),
+                ("topic", b"t".to_vec()),
+                ("partition", b"2".to_vec()),
+                ("offset", b"7".to_vec()),
+                ("headers", br#"{"x-request-id":"abc"}"#.to_vec()),
+                ("body", vec![b'{', b'}', 0xff]),
+            ]
+        );
+        let fields = entry(&route(r#"(jid-header . "x-request-id")"#), &record(Some("gone")));
+        assert_eq!(fields[0], ("id", b"t-abc".to_vec()));
+        assert_eq!(fields.last(), Some(&("error", b"gone".to_vec())));
+    }
+
+    #[test]
+    fn outcomes() {
+        assert_eq!(outcome(&record(None)), Outcome::Enqueued);
+        assert_eq!(outcome(&record(Some("gone"))), Outcome::DeadLettered);
+        assert_eq!(offsets_key("events"), "events:kafka-offsets");
+    }
+
+    #[test]
+    fn xadd() {
+        let packed = |route: &Route| {
+            let mut pipe = redis::pipe();
+            add_xadd(&mut pipe, route, &record(None));
+            String::from_utf8_lossy(&pipe.get_packed_pipeline()).into_owned()
+        };
+        let plain = packed(&route(""));
+        assert!(plain.starts_with("*15\r\n$4\r\nXADD\r\n$6\r\nevents\r\n$1\r\n*\r\n$2\r\nid\r\n"), "{:?}", plain);
+        let trimmed = packed(&route("(maxlen . 1000)"));
+        assert!(trimmed.starts_with("*18\r\n$4\r\nXADD\r\n$6\r\nevents\r\n$6\r\nMAXLEN\r\n$1\r\n~\r\n$4\r\n1000\r\n$1\r\n*\r\n"), "{:?}", trimmed);
+    }
+}
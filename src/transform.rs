@@ -0,0 +1,294 @@
+//! Changes to JSON request bodies before they're buffered, to keep card
+//! fingerprints, emails, and the like out of the buffer for its retention.
+//! Fields are named by JSON pointer, where a `*` token matches every
+//! element of an array or field of an object.  The body is written back
+//! without its whitespace, so it no longer matches any signature the
+//! sender made of it.
+
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::env;
+use tracing::*;
+
+lazy_static! {
+    static ref TRANSFORMED_BODIES: IntCounter =
+        register_int_counter!("body_transformed", "number of request bodies transformed before buffering").unwrap();
+    static ref TRANSFORM_REJECTED: IntCounter =
+        register_int_counter!("body_transform_rejected", "number of requests refused because their body could not be transformed").unwrap();
+    static ref TRANSFORM_PASSED: IntCounter =
+        register_int_counter!("body_transform_passed", "number of request bodies buffered as they came, because they could not be transformed").unwrap();
+    /// so hashes of guessable values, like emails, can't be reversed by hashing guesses
+    static ref HASH_SALT: String = env::var("TRANSFORM_HASH_SALT").unwrap_or_default();
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    Drop,
+    /// the hex SHA-256 of TRANSFORM_HASH_SALT and the value
+    Hash,
+    /// all but the last 4 characters replaced with *, or all of shorter values
+    Mask,
+    /// move the field to this key of the same object
+    Rename(String),
+}
+
+/// a JSON pointer, split into unescaped tokens
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pointer(Vec<String>);
+
+impl Pointer {
+    pub fn parse(s: &str) -> Result<Pointer, String> {
+        match s.strip_prefix('/') {
+            Some(rest) => Ok(Pointer(rest.split('/').map(|t| t.replace("~1", "/").replace("~0", "~")).collect())),
+            None => Err(format!("JSON pointer must start with /.  found {}", s)),
+        }
+    }
+}
+
+/// what to do with a body which can't be transformed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnError {
+    /// refuse the request
+    Reject,
+    /// buffer the body as it came
+    Pass,
+}
+
+#[derive(Clone, Debug)]
+pub struct BodyTransform {
+    /// applied in order
+    pub steps: Vec<(Pointer, Action)>,
+    pub on_error: OnError,
+}
+
+impl BodyTransform {
+    /// The transformed body, None to buffer the body as it came, or Err
+    /// explaining why the request should be refused.  Fields which aren't
+    /// in the body are skipped.
+    pub fn apply(&self, body: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        if body.is_empty() {
+            return Ok(None);
+        }
+        let transformed = serde_json::from_slice::<Value>(body)
+            .map_err(|err| format!("body is not JSON: {}", err))
+            .and_then(|mut json| {
+                for (pointer, action) in &self.steps {
+                    each_field(&mut json, &pointer.0, &mut |parent, key| apply_action(parent, key, action))?;
+                }
+                serde_json::to_vec(&json).map_err(|err| err.to_string())
+            });
+        match (transformed, self.on_error) {
+            (Ok(body), _) => {
+                TRANSFORMED_BODIES.inc();
+                Ok(Some(body))
+            }
+            (Err(err), OnError::Reject) => {
+                TRANSFORM_REJECTED.inc();
+                Err(err)
+            }
+            (Err(err), OnError::Pass) => {
+                warn!("buffering body untransformed: {}", err);
+                TRANSFORM_PASSED.inc();
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Call `f` with the parent and key of each field `tokens` points to.
+fn each_field(
+    value: &mut Value,
+    tokens: &[String],
+    f: &mut dyn FnMut(&mut Value, &str) -> Result<(), String>,
+) -> Result<(), String> {
+    let Some((token, rest)) = tokens.split_first() else {
+        return Ok(());
+    };
+    if rest.is_empty() {
+        if token != "*" {
+            return f(value, token);
+        }
+        // array elements from the end, so dropping one doesn't move the rest
+        let keys: Vec<String> = match value {
+            Value::Object(fields) => fields.keys().cloned().collect(),
+            Value::Array(elements) => (0..elements.len()).rev().map(|i| i.to_string()).collect(),
+            _ => Vec::new(),
+        };
+        for key in keys {
+            f(value, &key)?;
+        }
+        return Ok(());
+    }
+    match (value, token.as_str()) {
+        (Value::Object(fields), "*") => {
+            for v in fields.values_mut() {
+                each_field(v, rest, f)?;
+            }
+        }
+        (Value::Array(elements), "*") => {
+            for v in elements {
+                each_field(v, rest, f)?;
+            }
+        }
+        (Value::Object(fields), key) => {
+            if let Some(v) = fields.get_mut(key) {
+                each_field(v, rest, f)?;
+            }
+        }
+        (Value::Array(elements), index) => {
+            if let Some(v) = index.parse::<usize>().ok().and_then(|i| elements.get_mut(i)) {
+                each_field(v, rest, f)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+fn apply_action(parent: &mut Value, key: &str, action: &Action) -> Result<(), String> {
+    match parent {
+        Value::Object(fields) => match action {
+            Action::Drop => {
+                fields.remove(key);
+            }
+            // already named so, as a /* rename can find
+            Action::Rename(to) if key == to => (),
+            Action::Rename(to) => {
+                if fields.contains_key(key) && fields.contains_key(to) {
+                    return Err(format!("renaming {} would replace {}", key, to));
+                }
+                if let Some(v) = fields.remove(key) {
+                    fields.insert(to.clone(), v);
+                }
+            }
+            Action::Hash | Action::Mask => {
+                if let Some(v) = fields.get_mut(key) {
+                    *v = replacement(v, action)?;
+                }
+            }
+        },
+        Value::Array(elements) => {
+            let Some(i) = key.parse::<usize>().ok().filter(|i| *i < elements.len()) else {
+                return Ok(());
+            };
+            match action {
+                Action::Drop => {
+                    elements.remove(i);
+                }
+                Action::Rename(_) => return Err(format!("can't rename array element {}", key)),
+                Action::Hash | Action::Mask => elements[i] = replacement(&elements[i], action)?,
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// a hashed or masked value, or null for null
+fn replacement(value: &Value, action: &Action) -> Result<Value, String> {
+    let text = match value {
+        Value::Null => return Ok(Value::Null),
+        Value::String(s) => s.clone(),
+        Value::Number(n) => n.to_string(),
+        Value::Bool(_) | Value::Array(_) | Value::Object(_) if *action == Action::Hash => value.to_string(),
+        // not the value itself, which is meant to be kept out of logs too
+        _ => return Err("can only mask strings and numbers".to_string()),
+    };
+    match action {
+        Action::Hash => Ok(Value::String(format!("{:x}", Sha256::digest(format!("{}{}", *HASH_SALT, text))))),
+        _ => {
+            let len = text.chars().count();
+            let keep = if len > 4 { 4 } else { 0 };
+            let masked: String = text
+                .chars()
+                .enumerate()
+                .map(|(i, c)| if i < len - keep { '*' } else { c })
+                .collect();
+            Ok(Value::String(masked))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn transform(steps: &[(&str, Action)], on_error: OnError) -> BodyTransform {
+        BodyTransform {
+            steps: steps.iter().map(|(p, action)| (Pointer::parse(p).unwrap(), action.clone())).collect(),
+            on_error,
+        }
+    }
+
+    fn apply(transform: &BodyTransform, body: Value) -> Result<Option<Value>, String> {
+        let transformed = transform.apply(body.to_string().as_bytes())?;
+        Ok(transformed.map(|body| serde_json::from_slice(&body).unwrap()))
+    }
+
+    #[test]
+    fn actions() {
+        let t = transform(
+            &[
+                ("/card/fingerprint", Action::Drop),
+                ("/card/number", Action::Mask),
+                ("/customerId", Action::Rename("customer_id".to_string())),
+                ("/missing/field", Action::Drop),
+            ],
+            OnError::Reject,
+        );
+        let body = json!({"card": {"fingerprint": "abc", "number": "4242424242424242"}, "customerId": 7});
+        let expected = json!({"card": {"number": "************4242"}, "customer_id": 7});
+        assert_eq!(apply(&t, body), Ok(Some(expected)));
+    }
+
+    #[test]
+    fn wildcards() {
+        let t = transform(&[("/cards/*/number", Action::Mask), ("/items/*", Action::Drop)], OnError::Reject);
+        let body = json!({"cards": [{"number": 1234567}, {"number": "123"}, {"number": null}], "items": [1, 2, 3]});
+        let expected = json!({"cards": [{"number": "***4567"}, {"number": "***"}, {"number": null}], "items": []});
+        assert_eq!(apply(&t, body), Ok(Some(expected)));
+        let t = transform(&[("/tokens/*", Action::Mask)], OnError::Reject);
+        let expected = json!({"tokens": {"a": "******7890", "b": "****"}});
+        assert_eq!(apply(&t, json!({"tokens": {"a": "1234567890", "b": "abcd"}})), Ok(Some(expected)));
+    }
+
+    #[test]
+    fn hash() {
+        let t = transform(&[("/email", Action::Hash)], OnError::Reject);
+        // the salt goes first
+        let expected = format!("{:x}", Sha256::digest(format!("{}a@example.com", *HASH_SALT)));
+        assert_eq!(apply(&t, json!({"email": "a@example.com"})), Ok(Some(json!({"email": expected}))));
+    }
+
+    #[test]
+    fn errors() {
+        let reject = transform(&[("/card", Action::Mask)], OnError::Reject);
+        assert_eq!(apply(&reject, json!({"card": {"number": "4242"}})), Err("can only mask strings and numbers".to_string()));
+        assert!(reject.apply(b"not json").unwrap_err().starts_with("body is not JSON"));
+        // an empty body is left alone, rather than refused
+        assert_eq!(reject.apply(b""), Ok(None));
+        let pass = transform(&[("/card", Action::Mask)], OnError::Pass);
+        assert_eq!(pass.apply(b"not json"), Ok(None));
+        let rename = transform(&[("/a", Action::Rename("b".to_string()))], OnError::Reject);
+        assert_eq!(apply(&rename, json!({"a": 1, "b": 2})), Err("renaming a would replace b".to_string()));
+        let rename = transform(&[("/a/0", Action::Rename("b".to_string()))], OnError::Reject);
+        assert_eq!(apply(&rename, json!({"a": [1]})), Err("can't rename array element 0".to_string()));
+    }
+
+    #[test]
+    fn rename_to_itself() {
+        let t = transform(&[("/data/*", Action::Rename("payload".to_string()))], OnError::Reject);
+        assert_eq!(apply(&t, json!({"data": {"payload": 1}})), Ok(Some(json!({"data": {"payload": 1}}))));
+        let t = transform(&[("/payload", Action::Rename("payload".to_string()))], OnError::Reject);
+        assert_eq!(apply(&t, json!({"payload": 1})), Ok(Some(json!({"payload": 1}))));
+    }
+
+    #[test]
+    fn pointer() {
+        assert_eq!(Pointer::parse("/a~1b/c~0d"), Ok(Pointer(vec!["a/b".to_string(), "c~d".to_string()])));
+        assert!(Pointer::parse("a/b").is_err());
+    }
+}